@@ -1,4 +1,5 @@
-use serde;
+use crate::error::LoadError;
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct LlamaConfigJson {
     pub bos_token_id: u32,
@@ -17,6 +18,197 @@ pub(crate) struct LlamaConfigJson {
     pub torch_dtype: String,
     #[serde(default = "default_tie_word_embeddings")]
     pub tie_word_embeddings: bool,
+    // set by models whose heads are not hidden_size / num_attention_heads wide
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head_dim: Option<usize>,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScaling>,
+}
+
+// How rope frequencies are stretched for contexts longer than the model was
+// trained on, as in the rope_scaling of transformers
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "RopeScalingJson", into = "RopeScalingJson")]
+pub(crate) enum RopeScaling {
+    // every frequency is divided by factor
+    Linear {
+        factor: f32,
+    },
+    // theta grows once the sequence is longer than max_position_embeddings
+    Dynamic {
+        factor: f32,
+    },
+    // low frequencies are interpolated, high ones kept, and cos/sin scaled
+    Yarn {
+        factor: f32,
+        original_max_position_embeddings: Option<usize>,
+        beta_fast: f32,
+        beta_slow: f32,
+        attention_factor: f32,
+    },
+    // low frequencies are divided by factor, blending into unscaled high ones
+    Llama3 {
+        factor: f32,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+        original_max_position_embeddings: usize,
+    },
+}
+
+// rope_scaling as written in config.json; older configs say "type"
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct RopeScalingJson {
+    #[serde(alias = "type")]
+    rope_type: String,
+    factor: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_max_position_embeddings: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    low_freq_factor: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    high_freq_factor: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    beta_fast: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    beta_slow: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attention_factor: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mscale: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mscale_all_dim: Option<f32>,
+}
+
+impl TryFrom<RopeScalingJson> for RopeScaling {
+    type Error = String;
+
+    fn try_from(json: RopeScalingJson) -> Result<Self, String> {
+        let factor = json.factor.ok_or("rope_scaling has no factor")?;
+        let scaling = match json.rope_type.as_str() {
+            "linear" => RopeScaling::Linear { factor },
+            "dynamic" => RopeScaling::Dynamic { factor },
+            "yarn" => {
+                let mscale = |m: f32| {
+                    if factor <= 1. {
+                        1.
+                    } else {
+                        0.1 * m * factor.ln() + 1.
+                    }
+                };
+                let attention_factor =
+                    match (json.attention_factor, json.mscale, json.mscale_all_dim) {
+                        (Some(attention_factor), _, _) => attention_factor,
+                        (None, Some(m), Some(m_all)) if m != 0. && m_all != 0. => {
+                            mscale(m) / mscale(m_all)
+                        }
+                        _ => mscale(1.),
+                    };
+                RopeScaling::Yarn {
+                    factor,
+                    original_max_position_embeddings: json.original_max_position_embeddings,
+                    beta_fast: json.beta_fast.unwrap_or(32.),
+                    beta_slow: json.beta_slow.unwrap_or(1.),
+                    attention_factor,
+                }
+            }
+            "llama3" => {
+                let missing = |name: &str| format!("llama3 rope_scaling has no {name}");
+                let low_freq_factor = json
+                    .low_freq_factor
+                    .ok_or_else(|| missing("low_freq_factor"))?;
+                let high_freq_factor = json
+                    .high_freq_factor
+                    .ok_or_else(|| missing("high_freq_factor"))?;
+                if high_freq_factor <= low_freq_factor {
+                    return Err(
+                        "llama3 rope_scaling needs high_freq_factor > low_freq_factor".into(),
+                    );
+                }
+                RopeScaling::Llama3 {
+                    factor,
+                    low_freq_factor,
+                    high_freq_factor,
+                    original_max_position_embeddings: json
+                        .original_max_position_embeddings
+                        .ok_or_else(|| missing("original_max_position_embeddings"))?,
+                }
+            }
+            other => return Err(format!("unsupported rope_scaling type {other}")),
+        };
+        Ok(scaling)
+    }
+}
+
+impl From<RopeScaling> for RopeScalingJson {
+    fn from(scaling: RopeScaling) -> Self {
+        match scaling {
+            RopeScaling::Linear { factor } => RopeScalingJson {
+                rope_type: "linear".to_string(),
+                factor: Some(factor),
+                ..Default::default()
+            },
+            RopeScaling::Dynamic { factor } => RopeScalingJson {
+                rope_type: "dynamic".to_string(),
+                factor: Some(factor),
+                ..Default::default()
+            },
+            RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast,
+                beta_slow,
+                attention_factor,
+            } => RopeScalingJson {
+                rope_type: "yarn".to_string(),
+                factor: Some(factor),
+                original_max_position_embeddings,
+                beta_fast: Some(beta_fast),
+                beta_slow: Some(beta_slow),
+                attention_factor: Some(attention_factor),
+                ..Default::default()
+            },
+            RopeScaling::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings,
+            } => RopeScalingJson {
+                rope_type: "llama3".to_string(),
+                factor: Some(factor),
+                low_freq_factor: Some(low_freq_factor),
+                high_freq_factor: Some(high_freq_factor),
+                original_max_position_embeddings: Some(original_max_position_embeddings),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl LlamaConfigJson {
+    // length of a single q, k or v vector
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    // Check what the model relies on beyond what parsing ensures: heads to
+    // divide by, whole groups of q heads per kv head, and pairs for rope
+    pub fn validate(&self) -> Result<(), LoadError> {
+        let invalid = |reason: String| Err(LoadError::BadConfig(serde::de::Error::custom(reason)));
+        let (heads, kv_heads) = (self.num_attention_heads, self.num_key_value_heads);
+        if heads == 0 || kv_heads == 0 {
+            return invalid("num_attention_heads and num_key_value_heads must not be 0".into());
+        }
+        if !heads.is_multiple_of(kv_heads) {
+            return invalid(format!(
+                "num_attention_heads ({heads}) is not a multiple of num_key_value_heads ({kv_heads})"
+            ));
+        }
+        if !self.head_dim().is_multiple_of(2) {
+            return invalid(format!("head_dim ({}) must be even", self.head_dim()));
+        }
+        Ok(())
+    }
 }
 
 #[inline(always)]
@@ -33,3 +225,90 @@ const fn default_rope_theta() -> f32 {
 const fn default_tie_word_embeddings() -> bool {
     false
 }
+
+#[test]
+fn test_parse_rope_scaling() {
+    let parse = |json: serde_json::Value| serde_json::from_value::<Option<RopeScaling>>(json);
+    assert_eq!(parse(serde_json::Value::Null).unwrap(), None);
+    // older configs name the type "type"
+    assert_eq!(
+        parse(serde_json::json!({ "type": "linear", "factor": 2.0 })).unwrap(),
+        Some(RopeScaling::Linear { factor: 2. })
+    );
+    assert_eq!(
+        parse(serde_json::json!({ "rope_type": "dynamic", "factor": 2.0 })).unwrap(),
+        Some(RopeScaling::Dynamic { factor: 2. })
+    );
+    let Some(RopeScaling::Yarn {
+        beta_fast,
+        beta_slow,
+        attention_factor,
+        ..
+    }) = parse(serde_json::json!({ "rope_type": "yarn", "factor": 4.0 })).unwrap()
+    else {
+        panic!("expected yarn");
+    };
+    assert_eq!((beta_fast, beta_slow), (32., 1.));
+    assert!((attention_factor - (0.1 * 4f32.ln() + 1.)).abs() < 1e-6);
+    let llama3 = serde_json::json!({
+        "rope_type": "llama3",
+        "factor": 8.0,
+        "low_freq_factor": 1.0,
+        "high_freq_factor": 4.0,
+        "original_max_position_embeddings": 8192,
+    });
+    let scaling = parse(llama3.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&scaling).unwrap(), llama3);
+    assert!(parse(serde_json::json!({ "rope_type": "longrope", "factor": 4.0 })).is_err());
+    assert!(parse(serde_json::json!({ "rope_type": "llama3", "factor": 8.0 })).is_err());
+}
+
+#[test]
+fn test_validate_heads() {
+    use crate::params::{tiny_config, LLamaParams};
+    use std::collections::HashMap;
+    let mut config = tiny_config(1, true);
+    assert!(config.validate().is_ok());
+    config.num_attention_heads = 0;
+    assert!(matches!(config.validate(), Err(LoadError::BadConfig(_))));
+    // loading fails before it divides by the heads
+    let loaded = LLamaParams::<f32>::from_tensors(HashMap::new(), &config);
+    assert!(matches!(loaded, Err(LoadError::BadConfig(_))));
+    config.num_attention_heads = 2;
+    config.num_key_value_heads = 0;
+    assert!(matches!(config.validate(), Err(LoadError::BadConfig(_))));
+}
+
+#[test]
+fn test_validate_groups() {
+    let mut config = crate::params::tiny_config(1, true);
+    config.num_attention_heads = 4;
+    config.num_key_value_heads = 3;
+    config.head_dim = Some(2);
+    let Err(e) = config.validate() else {
+        panic!("4 heads don't split into 3 groups");
+    };
+    assert!(e
+        .to_string()
+        .contains("not a multiple of num_key_value_heads"));
+}
+
+#[test]
+fn test_validate_head_dim() {
+    let mut config = crate::params::tiny_config(1, true);
+    // 8 / 2 heads, or an explicit head_dim
+    config.head_dim = Some(3);
+    assert!(matches!(config.validate(), Err(LoadError::BadConfig(_))));
+    config.head_dim = None;
+    config.hidden_size = 6;
+    assert!(matches!(config.validate(), Err(LoadError::BadConfig(_))));
+}
+
+#[test]
+fn test_validate_rope_scaling() {
+    let mut config = crate::params::tiny_config(1, true);
+    config.rope_scaling = Some(RopeScaling::Linear { factor: 2. });
+    assert!(config.validate().is_ok());
+    config.rope_scaling = Some(RopeScaling::Dynamic { factor: 2. });
+    assert!(config.validate().is_ok());
+}
@@ -0,0 +1,170 @@
+use safetensors::{Dtype, SafeTensorError};
+use std::fmt;
+use std::path::PathBuf;
+
+// A tensor whose shape disagrees with the config
+#[derive(Debug)]
+pub struct ShapeMismatch {
+    pub name: String,
+    pub expected: Vec<usize>,
+    pub actual: Vec<usize>,
+}
+
+// Everything that can go wrong while loading a model directory
+#[derive(Debug)]
+pub enum LoadError {
+    // a model file could not be opened or read
+    MissingFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    // config.json is not a valid llama config
+    BadConfig(serde_json::Error),
+    // model.safetensors.index.json is not a valid shard index
+    BadIndex {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    // a model file is not valid safetensors
+    BadSafetensors {
+        path: PathBuf,
+        source: SafeTensorError,
+    },
+    // tensors the config requires but the checkpoint lacks
+    MissingTensor(Vec<String>),
+    // tensors whose shapes disagree with the config
+    ShapeMismatch(Vec<ShapeMismatch>),
+    // a GGUF file that can't be parsed or isn't a llama model
+    BadGguf {
+        path: PathBuf,
+        reason: String,
+    },
+    // a tensor stored in a dtype the loader can't convert
+    UnsupportedDtype {
+        name: String,
+        dtype: Dtype,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::MissingFile { path, source } => {
+                write!(f, "cannot read {}: {source}", path.display())
+            }
+            LoadError::BadConfig(e) => write!(f, "invalid config.json: {e}"),
+            LoadError::BadIndex { path, source } => {
+                write!(f, "invalid shard index {}: {source}", path.display())
+            }
+            LoadError::BadSafetensors { path, source } => {
+                write!(f, "invalid safetensors file {}: {source}", path.display())
+            }
+            LoadError::MissingTensor(names) => {
+                write!(f, "missing tensors: {}", names.join(", "))
+            }
+            LoadError::ShapeMismatch(mismatches) => {
+                write!(f, "tensor shapes disagree with the config:")?;
+                for ShapeMismatch {
+                    name,
+                    expected,
+                    actual,
+                } in mismatches
+                {
+                    write!(f, "\n  {name}: expected {expected:?}, found {actual:?}")?;
+                }
+                Ok(())
+            }
+            LoadError::BadGguf { path, reason } => {
+                write!(f, "invalid GGUF file {}: {reason}", path.display())
+            }
+            LoadError::UnsupportedDtype { name, dtype } => {
+                write!(f, "tensor {name} has unsupported dtype {dtype:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::MissingFile { source, .. } => Some(source),
+            LoadError::BadConfig(e) => Some(e),
+            LoadError::BadIndex { source, .. } => Some(source),
+            LoadError::BadSafetensors { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Everything that can go wrong while saving a model directory
+#[derive(Debug)]
+pub enum SaveError {
+    // a model file could not be written
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    // the tensors could not be serialized
+    Serialize(SafeTensorError),
+    // a dtype dense tensors can't be saved in
+    UnsupportedDtype(Dtype),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io { path, source } => {
+                write!(f, "cannot write {}: {source}", path.display())
+            }
+            SaveError::Serialize(e) => write!(f, "cannot serialize tensors: {e}"),
+            SaveError::UnsupportedDtype(dtype) => {
+                write!(f, "cannot save tensors as {dtype:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SaveError::Io { source, .. } => Some(source),
+            SaveError::Serialize(e) => Some(e),
+            SaveError::UnsupportedDtype(_) => None,
+        }
+    }
+}
+
+// A forward pass would put more positions in the KV cache than it can hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextFull {
+    pub cached: usize,    // positions already in the cache
+    pub requested: usize, // positions the forward pass would add
+    pub capacity: usize,  // positions the cache and the model can hold, or the pool has room for
+}
+
+impl fmt::Display for ContextFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "context full: {} cached + {} new positions exceed the {} the context holds",
+            self.cached, self.requested, self.capacity
+        )
+    }
+}
+
+impl std::error::Error for ContextFull {}
+
+// A checkpoint of another cache, or of positions overwritten since it was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleCheckpoint;
+
+impl fmt::Display for StaleCheckpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the checkpoint is of another cache, or the cache was overwritten since"
+        )
+    }
+}
+
+impl std::error::Error for StaleCheckpoint {}
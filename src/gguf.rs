@@ -0,0 +1,812 @@
+use crate::config::LlamaConfigJson;
+use crate::error::LoadError;
+use crate::params::{check_tensor_names, map_file, LLamaParams};
+use crate::tensor::{Float, Tensor};
+use half::{bf16, f16};
+use safetensors::Dtype;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: usize = 32;
+// ggml tensors have at most 4 dimensions
+const MAX_DIMS: u32 = 4;
+// how deep metadata arrays may nest
+const MAX_ARRAY_DEPTH: usize = 8;
+
+// ggml tensor types the loader can dequantize
+pub const GGML_TYPE_F32: u32 = 0;
+pub const GGML_TYPE_F16: u32 = 1;
+pub const GGML_TYPE_Q4_0: u32 = 2;
+pub const GGML_TYPE_Q8_0: u32 = 8;
+pub const GGML_TYPE_BF16: u32 = 30;
+
+// per-layer tensor names, "blk.{i}.*" in GGUF and "model.layers.{i}.*" in HF
+const LAYER_TENSORS: [(&str, &str); 9] = [
+    ("attn_norm.weight", "input_layernorm.weight"),
+    ("attn_q.weight", "self_attn.q_proj.weight"),
+    ("attn_k.weight", "self_attn.k_proj.weight"),
+    ("attn_v.weight", "self_attn.v_proj.weight"),
+    ("attn_output.weight", "self_attn.o_proj.weight"),
+    ("ffn_norm.weight", "post_attention_layernorm.weight"),
+    ("ffn_up.weight", "mlp.up_proj.weight"),
+    ("ffn_gate.weight", "mlp.gate_proj.weight"),
+    ("ffn_down.weight", "mlp.down_proj.weight"),
+];
+
+// A metadata value; integers and floats are widened
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Value::UInt(v) => usize::try_from(v).ok(),
+            Value::Int(v) => usize::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_f32(&self) -> Option<f32> {
+        match *self {
+            Value::Float(v) => Some(v as f32),
+            _ => None,
+        }
+    }
+}
+
+struct TensorInfo {
+    name: String,
+    shape: Vec<usize>, // outermost dimension first, as in safetensors
+    ggml_type: u32,
+    offset: usize, // relative to the start of the tensor data
+}
+
+// The header of a GGUF file: metadata and where each tensor is stored
+pub struct GgufFile {
+    metadata: HashMap<String, Value>,
+    tensors: Vec<TensorInfo>,
+    data_start: usize,
+}
+
+// Little-endian cursor over the header
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or_else(|| "unexpected end of file".to_string())?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        let len = self.u64()?;
+        usize::try_from(len).map_err(|_| format!("invalid length {len}"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    // A metadata value inside `depth` arrays
+    fn value(&mut self, value_type: u32, depth: usize) -> Result<Value, String> {
+        let value = match value_type {
+            0 => Value::UInt(self.take(1)?[0] as u64),
+            1 => Value::Int(self.take(1)?[0] as i8 as i64),
+            2 => Value::UInt(u16::from_le_bytes(self.take(2)?.try_into().unwrap()) as u64),
+            3 => Value::Int(i16::from_le_bytes(self.take(2)?.try_into().unwrap()) as i64),
+            4 => Value::UInt(self.u32()? as u64),
+            5 => Value::Int(self.u32()? as i32 as i64),
+            6 => Value::Float(f32::from_le_bytes(self.take(4)?.try_into().unwrap()) as f64),
+            7 => Value::Bool(self.take(1)?[0] != 0),
+            8 => Value::String(self.string()?),
+            9 if depth == MAX_ARRAY_DEPTH => {
+                return Err(format!("arrays nested more than {MAX_ARRAY_DEPTH} deep"))
+            }
+            9 => {
+                let item_type = self.u32()?;
+                let len = self.len()?;
+                // every item takes at least a byte, so the file bounds the loop
+                let mut items = Vec::new();
+                for _ in 0..len {
+                    items.push(self.value(item_type, depth + 1)?);
+                }
+                Value::Array(items)
+            }
+            10 => Value::UInt(self.u64()?),
+            11 => Value::Int(self.u64()? as i64),
+            12 => Value::Float(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            _ => return Err(format!("unknown metadata value type {value_type}")),
+        };
+        Ok(value)
+    }
+}
+
+// (values, bytes) per block of a ggml type
+fn block_layout(ggml_type: u32) -> Option<(usize, usize)> {
+    match ggml_type {
+        GGML_TYPE_F32 => Some((1, 4)),
+        GGML_TYPE_F16 | GGML_TYPE_BF16 => Some((1, 2)),
+        GGML_TYPE_Q4_0 => Some((32, 18)),
+        GGML_TYPE_Q8_0 => Some((32, 34)),
+        _ => None,
+    }
+}
+
+// The safetensors dtype of an unquantized ggml type
+fn ggml_dtype(ggml_type: u32) -> Option<Dtype> {
+    match ggml_type {
+        GGML_TYPE_F32 => Some(Dtype::F32),
+        GGML_TYPE_F16 => Some(Dtype::F16),
+        GGML_TYPE_BF16 => Some(Dtype::BF16),
+        _ => None,
+    }
+}
+
+// Expand ggml data to f32. Q8_0 blocks are an f16 scale and 32 i8 values;
+// Q4_0 blocks are an f16 scale and 32 nibbles, (q - 8) * d, with value j in
+// the low and value j + 16 in the high nibble of byte j.
+fn dequantize(ggml_type: u32, bytes: &[u8]) -> Vec<f32> {
+    let scale = |block: &[u8]| f16::from_le_bytes([block[0], block[1]]).to_f32();
+    match ggml_type {
+        GGML_TYPE_F32 => bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+        GGML_TYPE_F16 => bytes
+            .chunks_exact(2)
+            .map(|chunk| f16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+            .collect(),
+        GGML_TYPE_BF16 => bytes
+            .chunks_exact(2)
+            .map(|chunk| bf16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+            .collect(),
+        GGML_TYPE_Q8_0 => bytes
+            .chunks_exact(34)
+            .flat_map(|block| {
+                let d = scale(block);
+                block[2..].iter().map(move |&q| q as i8 as f32 * d)
+            })
+            .collect(),
+        GGML_TYPE_Q4_0 => bytes
+            .chunks_exact(18)
+            .flat_map(|block| {
+                let d = scale(block);
+                let qs = &block[2..];
+                let lo = qs.iter().map(move |&q| ((q & 0x0f) as f32 - 8.) * d);
+                let hi = qs.iter().map(move |&q| ((q >> 4) as f32 - 8.) * d);
+                lo.chain(hi)
+            })
+            .collect(),
+        _ => unreachable!("checked by block_layout"),
+    }
+}
+
+// convert_hf_to_gguf.py interleaves the two rotary halves of every q/k head;
+// put each head back in the HF order that `rope` expects
+fn unpermute(data: &[f32], n_heads: usize, cols: usize) -> Vec<f32> {
+    let head_dim = data.len() / cols / n_heads;
+    let half = head_dim / 2;
+    let mut out = vec![0.; data.len()];
+    for head in 0..n_heads {
+        for i in 0..half {
+            for j in 0..2 {
+                let src = head * head_dim + 2 * i + j;
+                let dst = head * head_dim + j * half + i;
+                out[dst * cols..][..cols].copy_from_slice(&data[src * cols..][..cols]);
+            }
+        }
+    }
+    out
+}
+
+// The HF name of a GGUF tensor
+fn hf_tensor_name(name: &str) -> Option<String> {
+    match name {
+        "token_embd.weight" => return Some("model.embed_tokens.weight".to_string()),
+        "output_norm.weight" => return Some("model.norm.weight".to_string()),
+        "output.weight" => return Some("lm_head.weight".to_string()),
+        _ => {}
+    }
+    let (layer, name) = name.strip_prefix("blk.")?.split_once('.')?;
+    let layer: usize = layer.parse().ok()?;
+    let (_, hf_name) = LAYER_TENSORS.iter().find(|(gguf, _)| *gguf == name)?;
+    Some(format!("model.layers.{layer}.{hf_name}"))
+}
+
+impl GgufFile {
+    // Parse the header of a GGUF v2 or v3 file
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(4)? != GGUF_MAGIC {
+            return Err("not a GGUF file".to_string());
+        }
+        let version = reader.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(format!("unsupported GGUF version {version}"));
+        }
+        let n_tensors = reader.len()?;
+        let n_metadata = reader.len()?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..n_metadata {
+            let key = reader.string()?;
+            let value_type = reader.u32()?;
+            metadata.insert(key, reader.value(value_type, 0)?);
+        }
+
+        let mut tensors = Vec::new();
+        for _ in 0..n_tensors {
+            let name = reader.string()?;
+            let n_dims = reader.u32()?;
+            if n_dims > MAX_DIMS {
+                return Err(format!("tensor {name} has {n_dims} dimensions"));
+            }
+            // ggml lists the innermost dimension first
+            let mut shape = (0..n_dims)
+                .map(|_| reader.len())
+                .collect::<Result<Vec<_>, _>>()?;
+            shape.reverse();
+            let ggml_type = reader.u32()?;
+            let offset = reader.len()?;
+            tensors.push(TensorInfo {
+                name,
+                shape,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = match metadata.get("general.alignment") {
+            Some(value) => value
+                .as_usize()
+                .filter(|&a| a > 0)
+                .ok_or("invalid general.alignment")?,
+            None => DEFAULT_ALIGNMENT,
+        };
+        let data_start = reader
+            .pos
+            .checked_next_multiple_of(alignment)
+            .ok_or("invalid general.alignment")?;
+        Ok(GgufFile {
+            metadata,
+            tensors,
+            data_start,
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<&Value, String> {
+        self.metadata
+            .get(key)
+            .ok_or_else(|| format!("missing metadata {key}"))
+    }
+
+    fn optional_usize(&self, key: &str) -> Result<Option<usize>, String> {
+        let value = self.metadata.get(key).map(|value| {
+            value
+                .as_usize()
+                .ok_or_else(|| format!("metadata {key} is not an unsigned integer"))
+        });
+        value.transpose()
+    }
+
+    fn optional_f32(&self, key: &str) -> Result<Option<f32>, String> {
+        let value = self.metadata.get(key).map(|value| {
+            value
+                .as_f32()
+                .ok_or_else(|| format!("metadata {key} is not a float"))
+        });
+        value.transpose()
+    }
+
+    fn get_usize(&self, key: &str) -> Result<usize, String> {
+        self.optional_usize(key)?
+            .ok_or_else(|| format!("missing metadata {key}"))
+    }
+
+    // Read the model hyperparameters the way config.json would provide them
+    pub fn config(&self) -> Result<LlamaConfigJson, String> {
+        let arch = match self.get("general.architecture")? {
+            Value::String(arch) => arch.as_str(),
+            _ => return Err("metadata general.architecture is not a string".to_string()),
+        };
+        if arch != "llama" {
+            return Err(format!("unsupported architecture {arch}"));
+        }
+        let key = |name: &str| format!("{arch}.{name}");
+
+        let num_attention_heads = self.get_usize(&key("attention.head_count"))?;
+        let num_key_value_heads = self
+            .optional_usize(&key("attention.head_count_kv"))?
+            .unwrap_or(num_attention_heads);
+        // older files only record the vocabulary size in the tokenizer
+        let vocab_size = match (
+            self.optional_usize(&key("vocab_size"))?,
+            self.metadata.get("tokenizer.ggml.tokens"),
+        ) {
+            (Some(vocab_size), _) => vocab_size,
+            (None, Some(Value::Array(tokens))) => tokens.len(),
+            _ => return Err(format!("missing metadata {}", key("vocab_size"))),
+        };
+        // llama.cpp's defaults for sentencepiece vocabularies
+        let bos_token_id = self
+            .optional_usize("tokenizer.ggml.bos_token_id")?
+            .unwrap_or(1) as u32;
+        let eos_token_id = self
+            .optional_usize("tokenizer.ggml.eos_token_id")?
+            .unwrap_or(2) as u32;
+        let rms_norm_eps = self
+            .optional_f32(&key("attention.layer_norm_rms_epsilon"))?
+            .ok_or_else(|| {
+                format!(
+                    "missing metadata {}",
+                    key("attention.layer_norm_rms_epsilon")
+                )
+            })?;
+        let rope_theta = self.optional_f32(&key("rope.freq_base"))?.unwrap_or(1e4);
+        // written as rope_scaling would be in config.json, to share its defaults
+        let rope_scaling = match self.metadata.get(&key("rope.scaling.type")) {
+            None => None,
+            Some(Value::String(rope_type)) if rope_type == "none" => None,
+            Some(Value::String(rope_type)) => {
+                let factor = self.optional_f32(&key("rope.scaling.factor"))?;
+                let original = self.optional_usize(&key("rope.scaling.original_context_length"))?;
+                let json = serde_json::json!({
+                    "rope_type": rope_type,
+                    "factor": factor,
+                    "original_max_position_embeddings": original,
+                });
+                Some(serde_json::from_value(json).map_err(|e| e.to_string())?)
+            }
+            Some(_) => {
+                return Err(format!(
+                    "metadata {} is not a string",
+                    key("rope.scaling.type")
+                ))
+            }
+        };
+
+        Ok(LlamaConfigJson {
+            bos_token_id,
+            eos_token_id,
+            hidden_size: self.get_usize(&key("embedding_length"))?,
+            intermediate_size: self.get_usize(&key("feed_forward_length"))?,
+            max_position_embeddings: self.get_usize(&key("context_length"))?,
+            num_attention_heads,
+            num_hidden_layers: self.get_usize(&key("block_count"))?,
+            num_key_value_heads,
+            vocab_size,
+            rms_norm_eps,
+            rope_theta,
+            // every tensor is converted through f32
+            torch_dtype: "float32".to_string(),
+            tie_word_embeddings: !self.tensors.iter().any(|t| t.name == "output.weight"),
+            head_dim: self.optional_usize(&key("attention.key_length"))?,
+            rope_scaling,
+        })
+    }
+}
+
+// Load the config and weights of a GGUF file. Tensors stored as T are used in
+// place; everything else is dequantized.
+pub fn load<T: Float>(path: &Path) -> Result<(LlamaConfigJson, LLamaParams<T>), LoadError> {
+    let bad_gguf = |reason: String| LoadError::BadGguf {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let mmap = map_file(path)?;
+    let gguf = GgufFile::parse(&mmap).map_err(bad_gguf)?;
+    let config = gguf.config().map_err(bad_gguf)?;
+    config.validate()?;
+
+    let mut by_hf_name = HashMap::new();
+    let mut unknown = vec![];
+    for info in &gguf.tensors {
+        match hf_tensor_name(&info.name) {
+            Some(name) => {
+                by_hf_name.insert(name, info);
+            }
+            None => unknown.push(info.name.as_str()),
+        }
+    }
+    if !unknown.is_empty() {
+        unknown.sort_unstable();
+        log::warn!("unexpected tensors in GGUF are ignored: {unknown:?}");
+    }
+    let present: HashSet<&str> = by_hf_name.keys().map(|s| s.as_str()).collect();
+    let names = check_tensor_names(&present, &config)?;
+
+    let mut tensors = HashMap::with_capacity(names.len());
+    for name in names {
+        let info = by_hf_name[name];
+        let (block_values, block_bytes) = block_layout(info.ggml_type).ok_or_else(|| {
+            bad_gguf(format!(
+                "tensor {} has unsupported type {}",
+                info.name, info.ggml_type
+            ))
+        })?;
+        let n_elements = (info.shape.iter()).try_fold(1usize, |n, &dim| n.checked_mul(dim));
+        let start = gguf.data_start.checked_add(info.offset);
+        let end = n_elements
+            .filter(|n| n.is_multiple_of(block_values))
+            .and_then(|n| (n / block_values).checked_mul(block_bytes))
+            .zip(start)
+            .and_then(|(len, start)| start.checked_add(len));
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) if end <= mmap.len() => (start, end),
+            _ => return Err(bad_gguf(format!("tensor {} is truncated", info.name))),
+        };
+
+        let n_heads = if name.ends_with("q_proj.weight") {
+            Some(config.num_attention_heads)
+        } else if name.ends_with("k_proj.weight") {
+            Some(config.num_key_value_heads)
+        } else {
+            None
+        };
+        // unpermute swaps the two rotary halves of each head's rows
+        if let Some(n_heads) = n_heads {
+            let split = match info.shape[..] {
+                [rows, cols] => n_heads > 0 && cols > 0 && rows.is_multiple_of(2 * n_heads),
+                _ => false,
+            };
+            if !split {
+                return Err(bad_gguf(format!(
+                    "tensor {} of shape {:?} doesn't split into {n_heads} heads",
+                    info.name, info.shape
+                )));
+            }
+        }
+        let mapped = ggml_dtype(info.ggml_type)
+            .filter(|&dtype| dtype == T::DTYPE && n_heads.is_none())
+            .and_then(|_| Tensor::mapped(mmap.clone(), start, &info.shape));
+        let tensor = match mapped {
+            Some(tensor) => tensor,
+            None => {
+                let mut data = dequantize(info.ggml_type, &mmap[start..end]);
+                if let Some(n_heads) = n_heads {
+                    data = unpermute(&data, n_heads, info.shape[1]);
+                }
+                Tensor::new(data.into_iter().map(T::from_f32).collect(), &info.shape)
+            }
+        };
+        tensors.insert(name.to_string(), tensor);
+    }
+    let params = LLamaParams::from_tensors(tensors, &config)?;
+    Ok((config, params))
+}
+
+// Write a llama GGUF file, the inverse of `load`. Tensors are keyed by their
+// HF name and stored with the given ggml type.
+#[cfg(test)]
+pub fn write_gguf(
+    config: &LlamaConfigJson,
+    tensors: &[(String, u32, &Tensor<f32>)],
+    path: &Path,
+) -> std::io::Result<()> {
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u64).to_le_bytes());
+        out.extend(s.as_bytes());
+    }
+    let mut metadata: Vec<(&str, u32, Vec<u8>)> = vec![];
+    let mut architecture = vec![];
+    string(&mut architecture, "llama");
+    metadata.push(("general.architecture", 8, architecture));
+    for (key, value) in [
+        ("llama.block_count", config.num_hidden_layers),
+        ("llama.embedding_length", config.hidden_size),
+        ("llama.feed_forward_length", config.intermediate_size),
+        ("llama.context_length", config.max_position_embeddings),
+        ("llama.attention.head_count", config.num_attention_heads),
+        ("llama.attention.head_count_kv", config.num_key_value_heads),
+        ("llama.vocab_size", config.vocab_size),
+        ("tokenizer.ggml.bos_token_id", config.bos_token_id as usize),
+        ("tokenizer.ggml.eos_token_id", config.eos_token_id as usize),
+    ] {
+        metadata.push((key, 4, (value as u32).to_le_bytes().to_vec()));
+    }
+    for (key, value) in [
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            config.rms_norm_eps,
+        ),
+        ("llama.rope.freq_base", config.rope_theta),
+    ] {
+        metadata.push((key, 6, value.to_le_bytes().to_vec()));
+    }
+    // the other types have parameters GGUF has no keys for
+    let rope_scaling = match config.rope_scaling {
+        Some(crate::config::RopeScaling::Linear { factor }) => Some(("linear", factor)),
+        Some(crate::config::RopeScaling::Dynamic { factor }) => Some(("dynamic", factor)),
+        _ => None,
+    };
+    if let Some((rope_type, factor)) = rope_scaling {
+        let mut value = vec![];
+        string(&mut value, rope_type);
+        metadata.push(("llama.rope.scaling.type", 8, value));
+        metadata.push((
+            "llama.rope.scaling.factor",
+            6,
+            factor.to_le_bytes().to_vec(),
+        ));
+    }
+
+    let mut infos = vec![];
+    let mut data = vec![];
+    for &(ref name, ggml_type, tensor) in tensors {
+        let gguf_name = match name.as_str() {
+            "model.embed_tokens.weight" => "token_embd.weight".to_string(),
+            "model.norm.weight" => "output_norm.weight".to_string(),
+            "lm_head.weight" => "output.weight".to_string(),
+            _ => {
+                let (layer, name) = name
+                    .strip_prefix("model.layers.")
+                    .unwrap()
+                    .split_once('.')
+                    .unwrap();
+                let (gguf, _) = LAYER_TENSORS.iter().find(|(_, hf)| *hf == name).unwrap();
+                format!("blk.{layer}.{gguf}")
+            }
+        };
+        // the inverse of `unpermute`
+        let mut values = tensor.data().to_vec();
+        let n_heads = match gguf_name.rsplit('.').nth(1).unwrap() {
+            "attn_q" => Some(config.num_attention_heads),
+            "attn_k" => Some(config.num_key_value_heads),
+            _ => None,
+        };
+        if let (Some(n_heads), &[rows, cols]) = (n_heads, &tensor.shape()[..]) {
+            let head_dim = rows / n_heads;
+            let half = head_dim / 2;
+            for head in 0..n_heads {
+                for i in 0..half {
+                    for j in 0..2 {
+                        let src = head * head_dim + j * half + i;
+                        let dst = head * head_dim + 2 * i + j;
+                        values[dst * cols..][..cols]
+                            .copy_from_slice(&tensor.data()[src * cols..][..cols]);
+                    }
+                }
+            }
+        }
+
+        data.resize(data.len().next_multiple_of(DEFAULT_ALIGNMENT), 0);
+        let mut info = vec![];
+        string(&mut info, &gguf_name);
+        info.extend((tensor.shape().len() as u32).to_le_bytes());
+        for &dim in tensor.shape().iter().rev() {
+            info.extend((dim as u64).to_le_bytes());
+        }
+        info.extend(ggml_type.to_le_bytes());
+        info.extend((data.len() as u64).to_le_bytes());
+        infos.push(info);
+
+        match ggml_type {
+            GGML_TYPE_F32 => data.extend(values.iter().flat_map(|x| x.to_le_bytes())),
+            GGML_TYPE_F16 => {
+                data.extend(values.iter().flat_map(|&x| f16::from_f32(x).to_le_bytes()))
+            }
+            GGML_TYPE_Q8_0 => {
+                for block in values.chunks_exact(32) {
+                    let max = block.iter().fold(0f32, |m, x| m.max(x.abs()));
+                    let d = f16::from_f32(max / 127.);
+                    let inv_d = if max > 0. { 1. / d.to_f32() } else { 0. };
+                    data.extend(d.to_le_bytes());
+                    data.extend(block.iter().map(|x| (x * inv_d).round() as i8 as u8));
+                }
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("can't write ggml type {ggml_type}"),
+                ))
+            }
+        }
+    }
+
+    let mut out = GGUF_MAGIC.to_vec();
+    out.extend(3u32.to_le_bytes());
+    out.extend((tensors.len() as u64).to_le_bytes());
+    out.extend((metadata.len() as u64).to_le_bytes());
+    for (key, value_type, value) in metadata {
+        string(&mut out, key);
+        out.extend(value_type.to_le_bytes());
+        out.extend(value);
+    }
+    for info in infos {
+        out.extend(info);
+    }
+    out.resize(out.len().next_multiple_of(DEFAULT_ALIGNMENT), 0);
+    out.extend(data);
+    std::fs::write(path, out)
+}
+
+#[test]
+fn test_dequantize_blocks() {
+    // Q8_0: scale 0.5, values 0..32
+    let mut q8 = f16::from_f32(0.5).to_le_bytes().to_vec();
+    q8.extend((0..32).map(|i| (i as i8 - 16) as u8));
+    let expected: Vec<f32> = (0..32).map(|i| (i as f32 - 16.) * 0.5).collect();
+    assert_eq!(dequantize(GGML_TYPE_Q8_0, &q8), expected);
+
+    // Q4_0: scale 2, byte j holds q = j % 16 in the low and 15 - j % 16 in the high nibble
+    let mut q4 = f16::from_f32(2.).to_le_bytes().to_vec();
+    q4.extend((0..16u8).map(|j| j | ((15 - j) << 4)));
+    let expected: Vec<f32> = (0..16)
+        .map(|j| (j as f32 - 8.) * 2.)
+        .chain((0..16).map(|j| (7. - j as f32) * 2.))
+        .collect();
+    assert_eq!(dequantize(GGML_TYPE_Q4_0, &q4), expected);
+
+    let f16s: Vec<u8> = [1.5f32, -2.]
+        .iter()
+        .flat_map(|&x| f16::from_f32(x).to_le_bytes())
+        .collect();
+    assert_eq!(dequantize(GGML_TYPE_F16, &f16s), vec![1.5, -2.]);
+}
+
+#[test]
+fn test_unpermute_heads() {
+    // 2 heads of dimension 4, one column: GGUF rows a0 b0 a1 b1 become a0 a1 b0 b1
+    let data = [0., 2., 1., 3., 4., 6., 5., 7.];
+    assert_eq!(unpermute(&data, 2, 1), vec![0., 1., 2., 3., 4., 5., 6., 7.]);
+}
+
+// A GGUF file of tiny_config(1, true) with the given k_proj, in the temp dir.
+// Tensors are all zeros.
+#[cfg(test)]
+fn tiny_gguf(file_name: &str, k_proj: Tensor<f32>) -> std::path::PathBuf {
+    tiny_gguf_with(&crate::params::tiny_config(1, true), file_name, k_proj)
+}
+
+// tiny_gguf of a config with the shapes of tiny_config(1, true)
+#[cfg(test)]
+fn tiny_gguf_with(
+    config: &LlamaConfigJson,
+    file_name: &str,
+    k_proj: Tensor<f32>,
+) -> std::path::PathBuf {
+    let zeros = |shape: &[usize]| Tensor::<f32>::default(shape);
+    let tensors = [
+        ("model.embed_tokens.weight", zeros(&[10, 8])),
+        ("model.norm.weight", zeros(&[8])),
+        ("model.layers.0.input_layernorm.weight", zeros(&[8])),
+        (
+            "model.layers.0.post_attention_layernorm.weight",
+            zeros(&[8]),
+        ),
+        ("model.layers.0.self_attn.q_proj.weight", zeros(&[8, 8])),
+        ("model.layers.0.self_attn.k_proj.weight", k_proj),
+        ("model.layers.0.self_attn.v_proj.weight", zeros(&[4, 8])),
+        ("model.layers.0.self_attn.o_proj.weight", zeros(&[8, 8])),
+        ("model.layers.0.mlp.up_proj.weight", zeros(&[16, 8])),
+        ("model.layers.0.mlp.gate_proj.weight", zeros(&[16, 8])),
+        ("model.layers.0.mlp.down_proj.weight", zeros(&[8, 16])),
+    ];
+    let tensors: Vec<_> = (tensors.iter())
+        .map(|(name, t)| (name.to_string(), GGML_TYPE_F32, t))
+        .collect();
+    let path = std::env::temp_dir().join(format!(
+        "learning-lm-rs-{}-{file_name}.gguf",
+        std::process::id()
+    ));
+    write_gguf(config, &tensors, &path).unwrap();
+    path
+}
+
+// Loads a GGUF file and deletes it
+#[cfg(test)]
+fn load_once(path: &Path) -> Result<(LlamaConfigJson, LLamaParams<f32>), LoadError> {
+    let result = load::<f32>(path);
+    std::fs::remove_file(path).unwrap();
+    result
+}
+
+#[test]
+fn test_truncated_header() {
+    let path = tiny_gguf("truncated", Tensor::default(&[4, 8]));
+    let bytes = std::fs::read(&path).unwrap();
+    assert!(load_once(&path).is_ok());
+    let data_start = GgufFile::parse(&bytes).unwrap().data_start;
+    for len in 0..data_start - DEFAULT_ALIGNMENT {
+        assert!(GgufFile::parse(&bytes[..len]).is_err(), "{len} bytes");
+    }
+}
+
+#[test]
+fn test_nested_arrays() {
+    let header = |depth: usize| {
+        let mut bytes = GGUF_MAGIC.to_vec();
+        bytes.extend(3u32.to_le_bytes());
+        bytes.extend(0u64.to_le_bytes()); // tensors
+        bytes.extend(1u64.to_le_bytes()); // metadata
+        bytes.extend(1u64.to_le_bytes());
+        bytes.push(b'a');
+        bytes.extend(9u32.to_le_bytes());
+        // each array holds one array, the innermost one a u8
+        for _ in 1..depth {
+            bytes.extend(9u32.to_le_bytes());
+            bytes.extend(1u64.to_le_bytes());
+        }
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend(1u64.to_le_bytes());
+        bytes.push(7);
+        bytes
+    };
+    let gguf = GgufFile::parse(&header(MAX_ARRAY_DEPTH)).unwrap();
+    assert!(matches!(gguf.get("a"), Ok(Value::Array(_))));
+    let err = GgufFile::parse(&header(MAX_ARRAY_DEPTH + 1)).err().unwrap();
+    assert!(err.contains("nested"), "{err}");
+    // far deeper than the stack could recurse
+    assert!(GgufFile::parse(&header(1_000_000)).is_err());
+    // an array longer than the file
+    let mut bytes = header(1);
+    let len = bytes.len() - 9;
+    bytes[len..len + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(GgufFile::parse(&bytes).is_err());
+}
+
+#[test]
+fn test_malformed_tensor_info() {
+    // the offset and the dimensions of k_proj, which the header stores after its name
+    let patch = |file_name: &str, offset: u64, dims: [u64; 2]| {
+        let path = tiny_gguf(file_name, Tensor::default(&[4, 8]));
+        let mut bytes = std::fs::read(&path).unwrap();
+        let name = b"blk.0.attn_k.weight";
+        let at = bytes.windows(name.len()).position(|w| w == name).unwrap() + name.len();
+        bytes[at + 4..at + 12].copy_from_slice(&dims[0].to_le_bytes());
+        bytes[at + 12..at + 20].copy_from_slice(&dims[1].to_le_bytes());
+        bytes[at + 24..at + 32].copy_from_slice(&offset.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        path
+    };
+    let bad = |path: std::path::PathBuf| {
+        let result = load_once(&path);
+        assert!(
+            matches!(result, Err(LoadError::BadGguf { .. })),
+            "{}",
+            path.display()
+        );
+    };
+    // an offset past the end of the address space
+    bad(patch("offset", u64::MAX - 8, [8, 4]));
+    // dimensions whose product overflows
+    bad(patch("dims", 0, [1 << 40, 1 << 40]));
+    // k_proj with one dimension, or rows that don't split into heads
+    bad(tiny_gguf("rank", Tensor::default(&[32])));
+    bad(tiny_gguf("heads", Tensor::default(&[3, 8])));
+}
+
+#[test]
+fn test_dynamic_rope_scaling() {
+    use crate::config::RopeScaling;
+    let mut config = crate::params::tiny_config(1, true);
+    config.rope_scaling = Some(RopeScaling::Dynamic { factor: 2. });
+    let path = tiny_gguf_with(&config, "dynamic", Tensor::default(&[4, 8]));
+    let (loaded, _) = load_once(&path).unwrap();
+    assert_eq!(loaded.rope_scaling, config.rope_scaling);
+}
@@ -1,43 +1,966 @@
-use std::{usize, vec};
-
+use crate::error::StaleCheckpoint;
 use crate::tensor::Tensor;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Tells caches apart, so that a checkpoint only restores the cache it came from
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
+
 pub struct KVCache<T> {
-    k_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    v_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    #[allow(unused)]
+    id: u64,
+    storage: Storage<T>,
     max_seq_len: usize,
     dim: usize,
     length: usize, // length of the current sequence
+    // the value of `clock` when each position was last written
+    written_at: Vec<u64>,
+    clock: u64, // bumped by every increment
+    sink_window: Option<SinkWindow>,
+    head: usize,  // ring index of the oldest window position
+    shift: usize, // positions the window slid by since clear_shift
+}
+
+enum Storage<T> {
+    // (max_seq_len, n_kv_head * dqkv) x layers for K and for V, allocated up front
+    Dense {
+        k: Vec<Tensor<T>>,
+        v: Vec<Tensor<T>>,
+    },
+    // the block table: blocks from a shared pool, in position order. Blocks
+    // may be shared with other caches, and are copied before they are written.
+    Paged {
+        pool: BlockPool<T>,
+        blocks: Vec<Arc<Block<T>>>,
+    },
+}
+
+// StreamingLLM: the first `sinks` positions stay for good, and after them a
+// ring buffer holds the most recent `window` ones. Positions are counted in
+// the cache, so they shift down as the window slides; the cache counts by how
+// much, and the model rotates its keys to match (see Llama::align_keys).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SinkWindow {
+    pub sinks: usize,
+    pub window: usize,
+}
+
+// A length the cache can be rolled back to, as long as none of the positions
+// before it have been overwritten since
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    cache: u64,
+    length: usize,
+    clock: u64,
+    shift: usize,
+}
+
+impl Checkpoint {
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.length
+    }
+}
+
+// Fixed-size blocks of K/V memory shared by the caches of many sequences. A
+// block holds K and V of `block_len` positions in every layer. Caches take
+// blocks as their sequences grow and give them back when they drop them;
+// freed blocks are reused before new ones are allocated.
+pub struct BlockPool<T>(Arc<PoolShared<T>>);
+
+struct PoolShared<T> {
+    n_layers: usize,
+    dim: usize,
+    block_len: usize,
+    max_blocks: usize,
+    state: Mutex<PoolState<T>>,
+}
+
+struct PoolState<T> {
+    free: Vec<Box<[T]>>,
+    in_use: usize,
+}
+
+// A block taken from a pool: K then V of each layer, (block_len, dim) each
+struct Block<T> {
+    data: Box<[T]>,
+    pool: BlockPool<T>,
+}
+
+impl<T> Clone for BlockPool<T> {
+    fn clone(&self) -> Self {
+        BlockPool(self.0.clone())
+    }
+}
+
+impl<T: Default + Copy> BlockPool<T> {
+    pub fn new(n_layers: usize, dim: usize, block_len: usize, max_blocks: usize) -> Self {
+        assert!(block_len > 0, "blocks must hold at least one position");
+        BlockPool(Arc::new(PoolShared {
+            n_layers,
+            dim,
+            block_len,
+            max_blocks,
+            state: Mutex::new(PoolState {
+                free: Vec::new(),
+                in_use: 0,
+            }),
+        }))
+    }
+
+    pub fn n_layers(&self) -> usize {
+        self.0.n_layers
+    }
+
+    pub fn dim(&self) -> usize {
+        self.0.dim
+    }
+
+    pub fn block_len(&self) -> usize {
+        self.0.block_len
+    }
+
+    // Blocks held by caches
+    #[allow(unused)]
+    pub fn in_use(&self) -> usize {
+        self.0.state.lock().unwrap().in_use
+    }
+
+    // Blocks that can still be taken
+    pub fn available(&self) -> usize {
+        self.0.max_blocks - self.0.state.lock().unwrap().in_use
+    }
+
+    fn take(&self) -> Option<Block<T>> {
+        let shared = &self.0;
+        let mut state = shared.state.lock().unwrap();
+        if state.in_use == shared.max_blocks {
+            return None;
+        }
+        state.in_use += 1;
+        let size = shared.n_layers * 2 * shared.block_len * shared.dim;
+        let data = state
+            .free
+            .pop()
+            .unwrap_or_else(|| vec![T::default(); size].into_boxed_slice());
+        Some(Block {
+            data,
+            pool: self.clone(),
+        })
+    }
+}
+
+impl<T> Drop for Block<T> {
+    fn drop(&mut self) {
+        let mut state = self.pool.0.state.lock().unwrap();
+        state.in_use -= 1;
+        state.free.push(std::mem::take(&mut self.data));
+    }
+}
+
+// The K or V rows of one layer in position order, read through however the
+// cache lays them out: runs of `block_len` rows of `dim` values, the last of
+// which may be partly filled
+pub struct Rows<'a, T> {
+    runs: Vec<&'a [T]>,
+    block_len: usize,
+    dim: usize,
+    len: usize,
+    ring: Option<(SinkWindow, usize)>, // a sink window and its head
+    sinks: &'a [T],                    // rows read instead of the first ones
+}
+
+impl<'a, T: Default + Copy> Rows<'a, T> {
+    pub fn new(runs: Vec<&'a [T]>, block_len: usize, dim: usize, len: usize) -> Self {
+        assert!(len <= runs.len() * block_len);
+        Rows {
+            runs,
+            block_len,
+            dim,
+            len,
+            ring: None,
+            sinks: &[],
+        }
+    }
+
+    // The same rows, with the first ones read from `sinks`
+    pub fn with_sinks(self, sinks: &'a [T]) -> Self {
+        assert!(sinks.len() <= self.len * self.dim);
+        Rows { sinks, ..self }
+    }
+
+    // The rows of a (len, dim) tensor
+    pub fn contiguous(t: &'a Tensor<T>) -> Self {
+        let (len, dim) = (t.shape()[0], t.size() / t.shape()[0].max(1));
+        Rows::new(vec![t.data()], len.max(1), dim, len)
+    }
+
+    #[allow(unused)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn row(&self, pos: usize) -> &'a [T] {
+        assert!(pos < self.len);
+        if pos * self.dim < self.sinks.len() {
+            return &self.sinks[pos * self.dim..][..self.dim];
+        }
+        let slot = ring_slot(self.ring, pos);
+        &self.runs[slot / self.block_len][slot % self.block_len * self.dim..][..self.dim]
+    }
+}
+
+// Storage row of position `pos` of a cache with an optional sink window
+fn ring_slot(ring: Option<(SinkWindow, usize)>, pos: usize) -> usize {
+    match ring {
+        Some((SinkWindow { sinks, window }, head)) if pos >= sinks => {
+            sinks + (head + pos - sinks) % window
+        }
+        _ => pos,
+    }
 }
 
 impl<T: Default + Copy> KVCache<T> {
     pub fn new(n_layers: usize, max_seq_len: usize, dim: usize, init_len: usize) -> Self {
+        let layers = || (0..n_layers).map(|_| Tensor::default(&[max_seq_len, dim]));
         KVCache {
-            k_cache: (0..n_layers)
-                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
-                .collect(),
-            v_cache: (0..n_layers)
-                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
-                .collect(),
-            max_seq_len: max_seq_len,
-            dim: dim,
+            id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            storage: Storage::Dense {
+                k: layers().collect(),
+                v: layers().collect(),
+            },
+            max_seq_len,
+            dim,
             length: init_len,
+            written_at: vec![0; init_len],
+            clock: 0,
+            sink_window: None,
+            head: 0,
+            shift: 0,
+        }
+    }
+
+    // A cache that never fills up: once it holds sinks + window positions,
+    // each new one evicts the oldest after the sinks
+    pub fn with_sink_window(n_layers: usize, sinks: usize, window: usize, dim: usize) -> Self {
+        assert!(window > 0, "the window must hold at least one position");
+        KVCache {
+            sink_window: Some(SinkWindow { sinks, window }),
+            ..Self::new(n_layers, sinks + window, dim, 0)
         }
     }
 
+    // A cache of up to max_seq_len positions that takes blocks from `pool` as
+    // the sequence grows, and gives them back when it shrinks or is dropped
+    pub fn paged(pool: &BlockPool<T>, max_seq_len: usize) -> Self {
+        KVCache {
+            id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            storage: Storage::Paged {
+                pool: pool.clone(),
+                blocks: Vec::new(),
+            },
+            max_seq_len,
+            dim: pool.dim(),
+            length: 0,
+            written_at: Vec::new(),
+            clock: 0,
+            sink_window: None,
+            head: 0,
+            shift: 0,
+        }
+    }
+
+    pub fn sink_window(&self) -> Option<SinkWindow> {
+        self.sink_window
+    }
+
+    pub fn is_paged(&self) -> bool {
+        matches!(self.storage, Storage::Paged { .. })
+    }
+
+    // The rows of positions start.. of a layer, which are contiguous unless the
+    // cache has a sink window. A paged cache has no such tensor.
     pub fn k_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.k_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
+        self.dense(0, layer)
+            .slice(start * self.dim, &[self.length - start, self.dim])
     }
 
     pub fn v_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.v_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
+        self.dense(1, layer)
+            .slice(start * self.dim, &[self.length - start, self.dim])
+    }
+
+    // K (kv = 0) or V (kv = 1) of a layer in a dense cache
+    fn dense(&self, kv: usize, layer: usize) -> &Tensor<T> {
+        match &self.storage {
+            Storage::Dense { k, v } => &[k, v][kv][layer],
+            Storage::Paged { .. } => panic!("a paged cache has no contiguous K and V"),
+        }
+    }
+
+    // Positions that can be added, evicting old ones if need be. A paged
+    // cache counts the blocks the pool has left, less the copy of a shared
+    // block it would append to.
+    pub fn room(&self) -> usize {
+        match (&self.storage, self.sink_window) {
+            (_, Some(SinkWindow { sinks, .. })) => self.max_seq_len - self.length.min(sinks),
+            (Storage::Paged { pool, blocks }, None) => {
+                let copy = self.shared_tail().is_some() as usize;
+                let blocks = (blocks.len() + pool.available()).saturating_sub(copy);
+                (self.max_seq_len.min(blocks * pool.block_len())).saturating_sub(self.length)
+            }
+            (Storage::Dense { .. }, None) => self.max_seq_len - self.length,
+        }
+    }
+
+    // Evicts the oldest window positions, or takes blocks from the pool, so
+    // that seq_len more fit. Checkpoints taken before an eviction can't be
+    // restored.
+    pub fn make_room(&mut self, seq_len: usize) {
+        assert!(
+            seq_len <= self.room(),
+            "{seq_len} positions don't fit in the cache"
+        );
+        let end = self.length + seq_len;
+        let shared_tail = self.shared_tail().filter(|_| seq_len > 0);
+        if let Storage::Paged { pool, blocks } = &mut self.storage {
+            // the sequences sharing the block appended to diverge here
+            if let Some(i) = shared_tail {
+                let mut copy = pool.take().expect("room() counted the copy");
+                copy.data.copy_from_slice(&blocks[i].data);
+                blocks[i] = Arc::new(copy);
+            }
+            while blocks.len() * pool.block_len() < end {
+                let block = pool.take().expect("room() counted the free blocks");
+                blocks.push(Arc::new(block));
+            }
+        }
+        let excess = end.saturating_sub(self.max_seq_len);
+        if let (Some(SinkWindow { window, .. }), true) = (self.sink_window, excess > 0) {
+            self.head = (self.head + excess) % window;
+            self.length -= excess;
+            self.shift += excess;
+            self.clock += 1;
+            self.written_at.fill(self.clock);
+        }
+    }
+
+    // The partly filled last block of a paged cache, if other caches share it
+    fn shared_tail(&self) -> Option<usize> {
+        match &self.storage {
+            Storage::Paged { pool, blocks } if !self.length.is_multiple_of(pool.block_len()) => {
+                let i = self.length / pool.block_len();
+                (Arc::strong_count(&blocks[i]) > 1).then_some(i)
+            }
+            _ => None,
+        }
+    }
+
+    // Storage row of position `pos`
+    fn slot(&self, pos: usize) -> usize {
+        ring_slot(self.sink_window.map(|w| (w, self.head)), pos)
+    }
+
+    // K (kv = 0) or V (kv = 1) of position `pos` in a layer
+    fn row_mut(&mut self, kv: usize, layer: usize, pos: usize) -> &mut [T] {
+        let (slot, dim) = (self.slot(pos), self.dim);
+        match &mut self.storage {
+            Storage::Dense { k, v } => {
+                let cache = if kv == 0 {
+                    &mut k[layer]
+                } else {
+                    &mut v[layer]
+                };
+                let data = unsafe { cache.data_mut() };
+                &mut data[slot * dim..][..dim]
+            }
+            Storage::Paged { pool, blocks } => {
+                let block_len = pool.block_len();
+                let row = ((layer * 2 + kv) * block_len + pos % block_len) * dim;
+                let block = Arc::get_mut(&mut blocks[pos / block_len])
+                    .expect("shared blocks are copied before they are written");
+                &mut block.data[row..][..dim]
+            }
+        }
+    }
+
+    // Writes the rows of k and v (seq, dim) at positions start.. of a layer
+    pub fn store(&mut self, layer: usize, start: usize, k: &Tensor<T>, v: &Tensor<T>) {
+        assert!(start + k.shape()[0] <= self.length && k.shape() == v.shape());
+        let rows = k
+            .data()
+            .chunks_exact(self.dim)
+            .zip(v.data().chunks_exact(self.dim));
+        for (i, (k, v)) in rows.enumerate() {
+            self.row_mut(0, layer, start + i).copy_from_slice(k);
+            self.row_mut(1, layer, start + i).copy_from_slice(v);
+        }
+    }
+
+    // K and V (len, dim) of a layer with the positions in order, copied out of
+    // the ring
+    #[cfg(test)]
+    pub fn gather(&self, layer: usize) -> (Tensor<T>, Tensor<T>) {
+        let copy = |rows: Rows<T>| {
+            let mut data = Vec::with_capacity(self.length * self.dim);
+            for pos in 0..self.length {
+                data.extend_from_slice(rows.row(pos));
+            }
+            Tensor::new(data, &[self.length, self.dim])
+        };
+        let (k, v) = self.rows(layer);
+        (copy(k), copy(v))
+    }
+
+    // K and V of a layer read in place: through the block table of a paged
+    // cache, through the ring of a sink window, or as the leading rows of a
+    // dense one
+    pub fn rows(&self, layer: usize) -> (Rows<'_, T>, Rows<'_, T>) {
+        let rows = |kv: usize| match &self.storage {
+            Storage::Dense { .. } if self.sink_window.is_some() => {
+                let data = self.dense(kv, layer).data();
+                Rows {
+                    ring: self.sink_window.map(|w| (w, self.head)),
+                    ..Rows::new(vec![data], self.max_seq_len, self.dim, self.length)
+                }
+            }
+            Storage::Dense { .. } => {
+                let data = &self.dense(kv, layer).data()[..self.length * self.dim];
+                Rows::new(vec![data], self.length.max(1), self.dim, self.length)
+            }
+            Storage::Paged { pool, blocks } => {
+                let size = pool.block_len() * self.dim;
+                let start = (layer * 2 + kv) * size;
+                let runs = blocks.iter().map(|b| &b.data[start..][..size]).collect();
+                Rows::new(runs, pool.block_len(), self.dim, self.length)
+            }
+        };
+        (rows(0), rows(1))
+    }
+
+    // K of position `pos` of a layer, to rotate in place
+    pub fn key_mut(&mut self, layer: usize, pos: usize) -> &mut [T] {
+        assert!(pos < self.length);
+        self.row_mut(0, layer, pos)
+    }
+
+    // Positions a sink window slid by since the last clear_shift
+    pub fn shift(&self) -> usize {
+        self.shift
+    }
+
+    // Called once the keys after the sinks have been turned back by shift().
+    // Checkpoints taken before can't be restored.
+    pub fn clear_shift(&mut self) {
+        self.shift = 0;
+        self.clock += 1;
+        let sinks = self.sink_window.map_or(0, |w| w.sinks).min(self.length);
+        self.written_at[sinks..self.length].fill(self.clock);
     }
 
     pub fn increment(&mut self, seq_len: usize) {
-        self.length += seq_len;
+        self.clock += 1;
+        let end = self.length + seq_len;
+        if self.written_at.len() < end {
+            self.written_at.resize(end, 0);
+        }
+        self.written_at[self.length..end].fill(self.clock);
+        self.length = end;
     }
 
     pub fn len(&self) -> usize {
         self.length
     }
+
+    // Positions the cache can hold
+    pub fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    // Drop every position from `len` on. The next forward continues from there.
+    // A paged cache returns the blocks it no longer needs to the pool.
+    pub fn truncate(&mut self, len: usize) {
+        assert!(
+            len <= self.length,
+            "can't truncate {} positions to {len}",
+            self.length
+        );
+        self.length = len;
+        // only sinks are left, and their keys don't depend on the shift
+        if self.sink_window.is_some_and(|w| len <= w.sinks) {
+            self.shift = 0;
+        }
+        if let Storage::Paged { pool, blocks } = &mut self.storage {
+            let kept = len.div_ceil(pool.block_len());
+            blocks.truncate(kept);
+            // what was past the kept blocks is gone for good
+            self.written_at
+                .truncate(self.written_at.len().min(kept * pool.block_len()));
+        }
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            cache: self.id,
+            length: self.length,
+            clock: self.clock,
+            shift: self.shift,
+        }
+    }
+
+    // Roll back (or forward, after a truncate) to a checkpoint of this cache.
+    // Fails, leaving the cache as it was, if a position before it was
+    // overwritten after it was taken.
+    pub fn restore(&mut self, checkpoint: Checkpoint) -> Result<(), StaleCheckpoint> {
+        let stale = match self.written_at.get(..checkpoint.length) {
+            Some(written_at) => written_at.iter().any(|&t| t > checkpoint.clock),
+            None => true,
+        };
+        if checkpoint.cache != self.id || stale {
+            return Err(StaleCheckpoint);
+        }
+        self.length = checkpoint.length;
+        self.shift = checkpoint.shift;
+        Ok(())
+    }
+}
+
+// Stored K/V blocks keyed by the tokens before them, so that sequences with a
+// common prefix, like a system prompt, compute it once. Each node of the trie
+// is a block, keyed by its tokens under the node of the full block before it;
+// only the last block of a prefix may be partly filled. The cache keeps at
+// most `max_blocks` blocks out of the pool: past that it evicts the least
+// recently used blocks no sequence shares, leaves first.
+pub struct PrefixCache<T> {
+    pool: BlockPool<T>,
+    roots: HashMap<Vec<u32>, PrefixNode<T>>,
+    max_blocks: usize,
+    n_blocks: usize,
+    clock: u64, // bumped by every lookup and insert
+    stats: PrefixStats,
+}
+
+struct PrefixNode<T> {
+    block: Arc<Block<T>>,
+    children: HashMap<Vec<u32>, PrefixNode<T>>,
+    last_used: u64,
+}
+
+// How much of the prompts looked up the prefix cache had
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    pub lookups: usize,
+    pub hits: usize,          // lookups that reused at least one position
+    pub tokens: usize,        // tokens looked up
+    pub cached_tokens: usize, // positions reused from the cache
+    pub evicted: usize,       // blocks given back to the pool
+}
+
+impl PrefixStats {
+    // Share of the lookups that were hits
+    pub fn hit_rate(&self) -> f32 {
+        self.hits as f32 / self.lookups.max(1) as f32
+    }
+
+    // Share of the tokens looked up that didn't need a forward pass
+    pub fn token_hit_rate(&self) -> f32 {
+        self.cached_tokens as f32 / self.tokens.max(1) as f32
+    }
+}
+
+impl<T: Default + Copy> PrefixCache<T> {
+    pub fn new(pool: &BlockPool<T>, max_blocks: usize) -> Self {
+        PrefixCache {
+            pool: pool.clone(),
+            roots: HashMap::new(),
+            max_blocks,
+            n_blocks: 0,
+            clock: 0,
+            stats: PrefixStats::default(),
+        }
+    }
+
+    pub fn pool(&self) -> &BlockPool<T> {
+        &self.pool
+    }
+
+    pub fn stats(&self) -> PrefixStats {
+        self.stats
+    }
+
+    // Blocks held by the cache
+    #[allow(unused)]
+    pub fn len(&self) -> usize {
+        self.n_blocks
+    }
+
+    // Gives the blocks back to the pool, once no sequence shares them
+    #[allow(unused)]
+    pub fn clear(&mut self) {
+        self.stats.evicted += self.n_blocks;
+        self.roots.clear();
+        self.n_blocks = 0;
+    }
+
+    // Evicts up to n of the least recently used blocks no sequence shares, for
+    // example when a sequence runs out of room in the pool. Returns how many
+    // went back to the pool.
+    pub fn evict(&mut self, n: usize) -> usize {
+        (0..n).take_while(|_| self.evict_one()).count()
+    }
+
+    fn evict_one(&mut self) -> bool {
+        // only leaves go, so that every node stays reachable from a root
+        fn oldest<'a, T>(
+            nodes: &'a HashMap<Vec<u32>, PrefixNode<T>>,
+            path: &mut Vec<&'a [u32]>,
+            best: &mut Option<(u64, Vec<&'a [u32]>)>,
+        ) {
+            for (key, node) in nodes {
+                path.push(key);
+                if !node.children.is_empty() {
+                    oldest(&node.children, path, best);
+                } else if Arc::strong_count(&node.block) == 1
+                    && best.as_ref().is_none_or(|(t, _)| node.last_used < *t)
+                {
+                    *best = Some((node.last_used, path.clone()));
+                }
+                path.pop();
+            }
+        }
+        let mut best = None;
+        oldest(&self.roots, &mut vec![], &mut best);
+        let Some((_, path)) = best else {
+            return false;
+        };
+        let path: Vec<Vec<u32>> = path.into_iter().map(|key| key.to_vec()).collect();
+        let (leaf, parents) = path.split_last().unwrap();
+        let mut nodes = &mut self.roots;
+        for key in parents {
+            nodes = &mut nodes.get_mut(key).unwrap().children;
+        }
+        nodes.remove(leaf);
+        self.n_blocks -= 1;
+        self.stats.evicted += 1;
+        true
+    }
+
+    // Evicts blocks until the cache is within max_blocks, or only shared ones
+    // are left
+    fn trim(&mut self) {
+        let excess = self.n_blocks.saturating_sub(self.max_blocks);
+        self.evict(excess);
+    }
+
+    // Starts an empty paged cache from the stored K/V of the longest prefix of
+    // `tokens` the cache has, and returns the number of positions it now holds.
+    // The prefix may end inside a block, which the cache shares until it
+    // writes to it. Leave the last token out of `tokens` if its logits are
+    // needed.
+    pub fn lookup(&mut self, tokens: &[u32], cache: &mut KVCache<T>) -> usize {
+        assert!(cache.len() == 0, "the cache must start empty");
+        let Storage::Paged { pool, blocks } = &mut cache.storage else {
+            panic!("only a paged cache can share blocks");
+        };
+        assert!(
+            Arc::ptr_eq(&pool.0, &self.pool.0),
+            "the cache has another pool"
+        );
+        let block_len = pool.block_len();
+        blocks.clear();
+        self.clock += 1;
+        let clock = self.clock;
+        let (mut nodes, mut matched) = (&mut self.roots, 0);
+        for chunk in tokens.chunks(block_len) {
+            // a whole block, or the block sharing the most tokens with the rest
+            let best = match nodes.contains_key(chunk) {
+                true => Some((chunk.to_vec(), chunk.len())),
+                false => (nodes.keys())
+                    .map(|key| (key, common_prefix(key, chunk)))
+                    .max_by_key(|&(_, common)| common)
+                    .map(|(key, common)| (key.clone(), common)),
+            };
+            let Some((key, common)) = best.filter(|&(_, common)| common > 0) else {
+                break;
+            };
+            let node = nodes.get_mut(&key).unwrap();
+            node.last_used = clock;
+            blocks.push(node.block.clone());
+            matched += common;
+            if common < block_len {
+                break;
+            }
+            nodes = &mut node.children;
+        }
+        cache.clock += 1;
+        cache.written_at = vec![cache.clock; matched];
+        cache.length = matched;
+
+        self.stats.lookups += 1;
+        self.stats.hits += (matched > 0) as usize;
+        self.stats.tokens += tokens.len();
+        self.stats.cached_tokens += matched;
+        // blocks of sequences that ended since may go now
+        self.trim();
+        matched
+    }
+
+    // Stores the blocks of `cache`, whose first positions hold `tokens`. The
+    // cache copies a partly filled last block before it appends to it. Blocks
+    // already stored for the same tokens are kept.
+    pub fn insert(&mut self, tokens: &[u32], cache: &KVCache<T>) {
+        assert!(
+            tokens.len() <= cache.len(),
+            "the cache doesn't hold the tokens"
+        );
+        let Storage::Paged { pool, blocks } = &cache.storage else {
+            panic!("only a paged cache can share blocks");
+        };
+        assert!(
+            Arc::ptr_eq(&pool.0, &self.pool.0),
+            "the cache has another pool"
+        );
+        self.clock += 1;
+        let mut nodes = &mut self.roots;
+        for (chunk, block) in tokens.chunks(pool.block_len()).zip(blocks) {
+            let node = nodes.entry(chunk.to_vec()).or_insert_with(|| {
+                self.n_blocks += 1;
+                PrefixNode {
+                    block: block.clone(),
+                    children: HashMap::new(),
+                    last_used: 0,
+                }
+            });
+            node.last_used = self.clock;
+            nodes = &mut node.children;
+        }
+        self.trim();
+    }
+}
+
+fn common_prefix(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[test]
+fn test_truncate_and_restore() {
+    let mut cache = KVCache::<f32>::new(1, 8, 2, 0);
+    let write = |cache: &mut KVCache<f32>, n: usize, value: f32| {
+        let start = cache.len();
+        cache.increment(n);
+        let mut k = cache.k_cache(0, start);
+        unsafe { k.data_mut() }.fill(value);
+    };
+    write(&mut cache, 3, 1.);
+    let prompt = cache.checkpoint();
+    write(&mut cache, 2, 2.);
+    let answer = cache.checkpoint();
+    assert_eq!(answer.len(), 5);
+
+    // drop a rejected draft token, then roll back to the prompt and forward again
+    cache.truncate(4);
+    assert_eq!(cache.len(), 4);
+    cache.restore(prompt).unwrap();
+    assert_eq!(cache.len(), 3);
+    cache.restore(answer).unwrap();
+    assert_eq!(
+        cache.k_cache(0, 0).data(),
+        &[1., 1., 1., 1., 1., 1., 2., 2., 2., 2.]
+    );
+
+    // regenerate the answer; the prompt is still intact
+    cache.restore(prompt).unwrap();
+    write(&mut cache, 1, 3.);
+    cache.restore(prompt).unwrap();
+    assert_eq!(cache.k_cache(0, 0).data(), &[1.; 6]);
+}
+
+#[test]
+fn test_restore_stale() {
+    let mut cache = KVCache::<f32>::new(1, 8, 2, 0);
+    cache.increment(5);
+    let checkpoint = cache.checkpoint();
+    cache.truncate(2);
+    cache.increment(1);
+    assert_eq!(cache.restore(checkpoint), Err(StaleCheckpoint));
+    assert_eq!(cache.len(), 3);
+
+    // a checkpoint of another cache, even one of the same length
+    let mut other = KVCache::<f32>::new(1, 8, 2, 0);
+    other.increment(3);
+    assert_eq!(other.restore(cache.checkpoint()), Err(StaleCheckpoint));
+
+    // sliding the window rewrites every position
+    let mut cache = KVCache::<f32>::with_sink_window(1, 2, 3, 2);
+    cache.make_room(5);
+    cache.increment(5);
+    let full = cache.checkpoint();
+    cache.make_room(1);
+    cache.increment(1);
+    assert_eq!(cache.restore(full), Err(StaleCheckpoint));
+    // the shift comes back with the positions after the sinks
+    let slid = cache.checkpoint();
+    cache.truncate(2);
+    let sinks = cache.checkpoint();
+    assert_eq!(cache.shift(), 0);
+    cache.restore(slid).unwrap();
+    assert_eq!(cache.shift(), 1);
+    // and once their keys are turned back, only the sinks are left as they were
+    cache.clear_shift();
+    assert_eq!(cache.restore(slid), Err(StaleCheckpoint));
+    cache.restore(sinks).unwrap();
+}
+
+#[test]
+fn test_sink_window_ring() {
+    // 2 sinks and a window of 3, with one value per position
+    let mut cache = KVCache::<f32>::with_sink_window(1, 2, 3, 1);
+    let append = |cache: &mut KVCache<f32>, values: &[f32]| {
+        cache.make_room(values.len());
+        let start = cache.len();
+        cache.increment(values.len());
+        let t = Tensor::new(values.to_vec(), &[values.len(), 1]);
+        cache.store(0, start, &t, &t);
+    };
+    append(&mut cache, &[0., 1., 2.]);
+    append(&mut cache, &[3., 4.]);
+    assert_eq!(cache.gather(0).0.data(), &[0., 1., 2., 3., 4.]);
+    // the oldest after the sinks go first, and the ring wraps around
+    append(&mut cache, &[5.]);
+    assert_eq!(cache.gather(0).0.data(), &[0., 1., 3., 4., 5.]);
+    append(&mut cache, &[6., 7.]);
+    assert_eq!(cache.gather(0).1.data(), &[0., 1., 5., 6., 7.]);
+    assert_eq!(cache.room(), 3);
+    // dropping the newest leaves the rest of the ring where it was
+    cache.truncate(3);
+    append(&mut cache, &[8., 9., 10.]);
+    assert_eq!(cache.gather(0).0.data(), &[0., 1., 8., 9., 10.]);
+    // read in place through the ring, in order
+    let (k, _) = cache.rows(0);
+    assert_eq!(
+        (0..5).map(|pos| k.row(pos)[0]).collect::<Vec<_>>(),
+        [0., 1., 8., 9., 10.]
+    );
+    assert_eq!(cache.shift(), 4);
+}
+
+#[test]
+fn test_paged_blocks() {
+    // blocks of 2 positions with one value each, 3 of them to share
+    let pool = BlockPool::<f32>::new(1, 1, 2, 3);
+    let (mut a, mut b) = (KVCache::paged(&pool, 8), KVCache::paged(&pool, 8));
+    let append = |cache: &mut KVCache<f32>, values: &[f32]| {
+        cache.make_room(values.len());
+        let start = cache.len();
+        cache.increment(values.len());
+        let t = Tensor::new(values.to_vec(), &[values.len(), 1]);
+        cache.store(0, start, &t, &t);
+    };
+    append(&mut a, &[0., 1., 2.]);
+    assert_eq!((pool.in_use(), a.room(), b.room()), (2, 3, 2));
+    append(&mut b, &[5.]);
+    append(&mut a, &[3.]);
+    let (k, _) = a.rows(0);
+    assert_eq!(
+        (0..4).map(|pos| k.row(pos)[0]).collect::<Vec<_>>(),
+        [0., 1., 2., 3.]
+    );
+    assert_eq!(a.gather(0).1.data(), &[0., 1., 2., 3.]);
+    assert_eq!(a.room(), 0);
+
+    // a checkpoint inside the kept blocks survives a truncate, one past them doesn't
+    let whole = a.checkpoint();
+    a.truncate(3);
+    assert_eq!(pool.in_use(), 3);
+    a.restore(whole).unwrap();
+    a.truncate(1);
+    assert_eq!(pool.in_use(), 2);
+    assert_eq!(a.restore(whole), Err(StaleCheckpoint));
+}
+
+#[test]
+fn test_prefix_sharing() {
+    // blocks of 2 positions with one value each; a position's value is its token
+    let pool = BlockPool::<f32>::new(1, 1, 2, 8);
+    let mut prefixes = PrefixCache::new(&pool, 8);
+    let append = |cache: &mut KVCache<f32>, tokens: &[u32]| {
+        cache.make_room(tokens.len());
+        let start = cache.len();
+        cache.increment(tokens.len());
+        let values = tokens.iter().map(|&t| t as f32).collect();
+        let t = Tensor::new(values, &[tokens.len(), 1]);
+        cache.store(0, start, &t, &t);
+    };
+    let mut a = KVCache::paged(&pool, 8);
+    assert_eq!(prefixes.lookup(&[1, 2, 3], &mut a), 0);
+    append(&mut a, &[1, 2, 3, 4, 5]);
+    prefixes.insert(&[1, 2, 3, 4, 5], &a);
+    assert_eq!((prefixes.len(), pool.in_use()), (3, 3));
+    // a keeps writing to a copy of its last block
+    append(&mut a, &[6]);
+    assert_eq!(pool.in_use(), 4);
+
+    // b diverges inside the second block, which it shares until it writes
+    let mut b = KVCache::paged(&pool, 8);
+    assert_eq!(prefixes.lookup(&[1, 2, 3, 9, 9], &mut b), 3);
+    assert_eq!(pool.in_use(), 4);
+    append(&mut b, &[9, 9]);
+    assert_eq!(pool.in_use(), 6);
+    assert_eq!(b.gather(0).0.data(), &[1., 2., 3., 9., 9.]);
+    assert_eq!(a.gather(0).0.data(), &[1., 2., 3., 4., 5., 6.]);
+
+    // a whole stored prefix, and one the cache doesn't have
+    let mut c = KVCache::paged(&pool, 8);
+    assert_eq!(prefixes.lookup(&[1, 2, 3, 4, 5], &mut c), 5);
+    let mut d = KVCache::paged(&pool, 8);
+    assert_eq!(prefixes.lookup(&[2, 1], &mut d), 0);
+    let stats = prefixes.stats();
+    assert_eq!(
+        (stats.lookups, stats.hits, stats.tokens, stats.cached_tokens),
+        (4, 2, 15, 8)
+    );
+    assert_eq!(stats.hit_rate(), 0.5);
+    assert_eq!(stats.token_hit_rate(), 8. / 15.);
+
+    // the blocks go back once neither the caches nor the prefix cache hold them
+    drop((a, b, c, d));
+    assert_eq!(pool.in_use(), 3);
+    prefixes.clear();
+    assert_eq!(pool.in_use(), 0);
+}
+
+#[test]
+fn test_prefix_eviction() {
+    let pool = BlockPool::<f32>::new(1, 1, 2, 8);
+    let mut prefixes = PrefixCache::new(&pool, 2);
+    let sequence = |prefixes: &mut PrefixCache<f32>, tokens: &[u32]| {
+        let mut cache = KVCache::paged(&pool, 8);
+        cache.make_room(tokens.len());
+        cache.increment(tokens.len());
+        let t = Tensor::new(
+            tokens.iter().map(|&t| t as f32).collect(),
+            &[tokens.len(), 1],
+        );
+        cache.store(0, 0, &t, &t);
+        prefixes.insert(tokens, &cache);
+        cache
+    };
+    // blocks the sequences still use stay past the budget
+    let a = sequence(&mut prefixes, &[1, 2, 3, 4]);
+    let b = sequence(&mut prefixes, &[5, 6, 7, 8]);
+    assert_eq!((prefixes.len(), pool.in_use()), (4, 4));
+
+    // once a ends, its blocks are the least recently used, leaf first
+    drop(a);
+    let mut c = KVCache::paged(&pool, 8);
+    assert_eq!(prefixes.lookup(&[5, 6, 7], &mut c), 3);
+    assert_eq!((prefixes.len(), pool.in_use()), (2, 2));
+    assert_eq!(prefixes.evict(5), 0);
+
+    drop((b, c));
+    assert_eq!(prefixes.evict(1), 1);
+    let mut d = KVCache::paged(&pool, 8);
+    assert_eq!(prefixes.lookup(&[5, 6, 7], &mut d), 2);
+    assert_eq!((prefixes.len(), pool.in_use()), (1, 1));
+    assert_eq!(prefixes.stats().evicted, 3);
 }
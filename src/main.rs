@@ -1,78 +1,233 @@
 extern crate core;
 
 mod config;
+mod error;
+mod gguf;
 mod kvcache;
 mod model;
 mod operators;
 mod params;
+mod quant;
+mod simd;
 mod tensor;
+#[cfg(test)]
+mod test_util;
 
+use crate::kvcache::{KVCache, PrefixCache};
+use crate::model::{Llama, OverflowPolicy, PREFILL_CHUNK};
 use std::collections::HashMap;
 use std::io;
 use std::io::Write;
 use std::path::PathBuf;
 use tokenizers::Tokenizer;
-use crate::kvcache::KVCache;
-use crate::model::Llama;
+
+// Tokens ChatManager lets an answer run to
+const MAX_ANSWER_LEN: usize = 100;
+// Positions in a block of ChatManager's paged caches
+const KV_BLOCK_LEN: usize = 16;
 
 pub struct ChatManager {
     pub messages: Vec<String>,
     pub kv_cache: KVCache<f32>,
+    pub prefix_cache: PrefixCache<f32>,
     pub history: HashMap<u32, Vec<u32>>,
     pub llama: Llama<f32>,
     pub tokenizer: Tokenizer,
+    pub overflow: OverflowPolicy,
 }
 
 impl ChatManager {
     fn new(llama: Llama<f32>, tk: Tokenizer) -> Self {
+        // one conversation, the copy of the block it shares with the prefix
+        // cache, and as many blocks again for the prefix cache to keep
+        let per_sequence = llama.max_seq_len().div_ceil(KV_BLOCK_LEN);
+        let pool = llama.new_block_pool(KV_BLOCK_LEN, 2 * per_sequence + 1);
         ChatManager {
             messages: vec![],
-            kv_cache: llama.new_cache(),
+            kv_cache: llama.new_paged_cache(&pool),
+            prefix_cache: PrefixCache::new(&pool, per_sequence),
             history: HashMap::new(),
             llama,
             tokenizer: tk,
+            overflow: OverflowPolicy::default(),
         }
     }
 
-    fn run(&mut self) {
-        loop {
-            self.messages
-                .push("user: hello. AI is chatting with user\n".to_string());
-
-            self.messages.push("AI: nice to meet you\n".to_string());
+    // Chats over the lines of `input` until it ends
+    fn run(&mut self, mut input: impl io::BufRead) {
+        self.messages
+            .push("user: hello. AI is chatting with user\n".to_string());
 
-            self.messages
-                .push("user: please chat wite me\n".to_string());
+        self.messages.push("AI: nice to meet you\n".to_string());
 
-            self.messages.push("AI: OK\n".to_string());
+        self.messages
+            .push("user: please chat wite me\n".to_string());
 
+        self.messages.push("AI: OK\n".to_string());
+        let preamble_len = self.messages.len();
+        let context_len = self.llama.context_len(&self.kv_cache);
+        // a sink window cache keeps the conversation going forever, so each turn
+        // only feeds what is new; otherwise the whole conversation is encoded
+        // again every turn, and only what the prefix cache doesn't have is fed
+        let sinks = self.kv_cache.sink_window().map(|w| w.sinks);
+        let streaming = sinks.is_some();
+        let mut unfed = if streaming {
+            self.encode(&self.messages.join(""))
+        } else {
+            vec![]
+        };
+        // the sink window cache before the last turn was fed, and what it was fed
+        let mut last_turn = None;
 
+        loop {
             print!("user: ");
             io::stdout().flush().unwrap();
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).unwrap();
-            let input = input.trim();
+            let mut line = String::new();
+            if input.read_line(&mut line).unwrap() == 0 {
+                println!(); // end of input
+                return;
+            }
+            let input = line.trim();
+            // what a refused turn puts back
+            let (messages, fed_before) = (self.messages.clone(), unfed.clone());
+            let (last_before, cache_before) = (last_turn.clone(), self.kv_cache.checkpoint());
+            // /retry answers the last message again
+            let retry = input == "/retry";
+            if !retry {
+                self.messages.push(format!("user: {}\n", input));
+            } else if self.messages.len() > preamble_len {
+                self.messages.pop(); // the answer
+            } else {
+                println!("(nothing to retry)");
+                continue;
+            }
 
-            let prompt = self.messages.join("") + "AI: ";
-            let binding = self.tokenizer.encode(prompt, false).unwrap();
-            let input_ids = binding.get_ids();
+            let (input_ids, cached) = if streaming {
+                if !retry {
+                    unfed.extend(self.encode(&format!("user: {}\nAI: ", input)));
+                } else if let Some((before, fed)) = last_turn.take() {
+                    match self.kv_cache.restore(before) {
+                        Ok(()) => unfed = fed,
+                        // the window slid since, so the conversation is fed again
+                        Err(_) => {
+                            self.kv_cache =
+                                self.llama.new_sink_window_cache(sinks.unwrap()).unwrap();
+                            unfed = self.encode_prompt();
+                        }
+                    }
+                }
+                last_turn = Some((self.kv_cache.checkpoint(), unfed.clone()));
+                (unfed.clone(), 0)
+            } else {
+                let mut input_ids = self.encode_prompt();
+                if self.overflow == OverflowPolicy::TruncateOldest {
+                    // drop the oldest exchanges after the preamble until the answer fits
+                    while input_ids.len() + MAX_ANSWER_LEN > context_len
+                        && self.messages.len() > preamble_len + 1
+                    {
+                        self.messages.drain(preamble_len..preamble_len + 2);
+                        input_ids = self.encode_prompt();
+                    }
+                }
+                // a fresh cache that starts with as much of the prompt as is cached,
+                // and is stored for the next turn; the last token is left to be fed
+                // for its logits
+                self.kv_cache = self.llama.new_paged_cache(self.prefix_cache.pool());
+                let prompt_len = input_ids.len() - 1;
+                let prompt = &input_ids[..prompt_len];
+                let cached = self.prefix_cache.lookup(prompt, &mut self.kv_cache);
+                let fed = cached == prompt.len()
+                    || self
+                        .llama
+                        .prefill(&prompt[cached..], &mut self.kv_cache, PREFILL_CHUNK)
+                        .is_ok();
+                if fed {
+                    self.prefix_cache.insert(prompt, &self.kv_cache);
+                    (input_ids, prompt_len)
+                } else {
+                    // too long to cache; answer slides over it, or refuses it
+                    self.kv_cache.truncate(0);
+                    (input_ids, 0)
+                }
+            };
 
-            let output_ids = self.llama.answer(input_ids, 100, 0.8, 30, 1., &mut self.kv_cache);
-            let resp = self.tokenizer.decode(&output_ids, true).unwrap();
+            let input_ids = &input_ids[cached..];
+            let output_ids = match self.llama.answer(
+                input_ids,
+                MAX_ANSWER_LEN,
+                0.8,
+                30,
+                1.,
+                self.overflow,
+                &mut self.kv_cache,
+            ) {
+                Ok(output_ids) => output_ids,
+                Err(full) => {
+                    // the turn is refused, and the conversation stays as it was
+                    println!("({full}; --overflow truncate-oldest or slide makes room)");
+                    self.messages = messages;
+                    unfed = fed_before;
+                    last_turn = last_before;
+                    // a retry that had to start the sink window over begins
+                    // the conversation again
+                    if streaming && self.kv_cache.restore(cache_before).is_err() {
+                        self.kv_cache = self.llama.new_sink_window_cache(sinks.unwrap()).unwrap();
+                        unfed = self.encode(&self.messages.join(""));
+                        last_turn = None;
+                    }
+                    continue;
+                }
+            };
+            // answer returns the prompt and a start token before the answer
+            let answer_ids = &output_ids[input_ids.len() + 1..];
+            let resp = self.tokenizer.decode(answer_ids, true).unwrap();
+            if streaming {
+                unfed.clear();
+                // the last token is only fed to the cache if it didn't end the answer
+                if answer_ids.len() == MAX_ANSWER_LEN {
+                    unfed.push(answer_ids[MAX_ANSWER_LEN - 1]);
+                }
+                unfed.extend(self.encode("\n"));
+            }
 
             self.messages
                 .push(format!("AI: {}\n", resp));
             println!("AI: {}", resp);
         }
     }
-}
 
+    fn encode_prompt(&self) -> Vec<u32> {
+        self.encode(&(self.messages.join("") + "AI: "))
+    }
+
+    fn encode(&self, text: &str) -> Vec<u32> {
+        self.tokenizer
+            .encode(text, false)
+            .unwrap()
+            .get_ids()
+            .to_vec()
+    }
+}
 
 
 fn main() {
+    // --threads N: threads for the matmuls and attention, one per core by default
+    if let Some(threads) = arg("--threads") {
+        operators::set_num_threads(threads);
+    }
     let project_dir = env!("CARGO_MANIFEST_DIR");
     let model_dir = PathBuf::from(project_dir).join("models").join("story");
-    let llama = model::Llama::<f32>::from_safetensors(&model_dir);
+    let mut llama = match model::Llama::<f32>::from_safetensors(&model_dir) {
+        Ok(llama) => llama,
+        Err(e) => {
+            eprintln!("failed to load {}: {e}", model_dir.display());
+            std::process::exit(1);
+        }
+    };
+    // --attention full|streaming[:block]: how attention is computed
+    if let Some(attention) = arg("--attention") {
+        llama.set_attention(attention);
+    }
     let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
     let input = "Once upon a time";
     let binding = tokenizer.encode(input, false).unwrap();
@@ -82,5 +237,49 @@ fn main() {
     println!("{}", tokenizer.decode(&output_ids, true).unwrap());
 
     println!("\n---------chatbot-------------");
-    ChatManager::new(llama, tokenizer).run(); // 启动对话管理器
+    let mut chat = ChatManager::new(llama, tokenizer);
+    // --sink-window N: N attention sinks and a sliding window, so that the chat
+    // never runs out of context, instead of the prefix cache
+    if let Some(sinks) = arg("--sink-window") {
+        chat.kv_cache = match chat.llama.new_sink_window_cache(sinks) {
+            Some(cache) => cache,
+            None => {
+                eprintln!("invalid value for --sink-window: {sinks}");
+                std::process::exit(2);
+            }
+        };
+    }
+    // --overflow stop|truncate-oldest|slide: what happens once the conversation
+    // fills the context
+    if let Some(overflow) = arg("--overflow") {
+        chat.overflow = overflow;
+    }
+    chat.run(io::stdin().lock()); // 启动对话管理器
+}
+
+// The value after `name` on the command line; exits if it doesn't parse
+fn arg<V: std::str::FromStr>(name: &str) -> Option<V> {
+    let mut args = std::env::args().skip_while(|a| a != name).skip(1);
+    let value = args.next()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            eprintln!("invalid value for {name}: {value}");
+            std::process::exit(2);
+        }
+    }
+}
+
+#[test]
+fn test_retry_after_refused_turn() {
+    use crate::test_util::{story_dir, story_model};
+    let tokenizer = Tokenizer::from_file(story_dir().join("tokenizer.json")).unwrap();
+    let mut chat = ChatManager::new(story_model(), tokenizer);
+    // the second line doesn't fit in the context, so its turn is refused
+    let long = "once upon a time ".repeat(200);
+    chat.run(format!("hello\n{long}\n/retry\n").as_bytes());
+    // the retry answered the first line again, after the preamble
+    assert_eq!(chat.messages.len(), 6);
+    assert_eq!(chat.messages[4], "user: hello\n");
+    assert!(chat.messages[5].starts_with("AI: "));
 }
@@ -1,76 +1,378 @@
 use std::fs::File;
+use std::io::BufReader;
 use std::vec;
 
-use crate::config::LlamaConfigJson;
-use crate::kvcache::KVCache;
+use crate::config::{LlamaConfigJson, RopeScaling};
+use crate::error::{ContextFull, LoadError, SaveError};
+use crate::gguf;
+use crate::kvcache::{BlockPool, KVCache, Rows, SinkWindow};
 use crate::operators as OP;
-use crate::operators::{dot, masked_softmax, matmul_transb, rms_norm, swiglu};
-use crate::params::LLamaParams;
-use crate::tensor::Tensor;
-use safetensors::SafeTensors;
+use crate::operators::{linear, masked_softmax_row, rms_norm, swiglu};
+use crate::params::{torch_dtype_name, write_atomic, LLamaParams, LoadOptions, SaveOptions};
+use crate::quant::Weight;
+use crate::simd;
+use crate::tensor::{Float, Tensor};
+use rayon::prelude::*;
 use std::path::Path;
-use std::process::id;
+
+// Tokens forward feeds at a time, so that the scores of full attention stay
+// small for long prompts
+pub const PREFILL_CHUNK: usize = 64;
+
+// How forward computes attention
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Attention {
+    // all (seq, total_seq) scores of every head, then the softmax
+    #[default]
+    Full,
+    // online softmax over blocks of `block` keys, without storing the scores
+    Streaming {
+        block: usize,
+    },
+}
+
+// "full", "streaming", or "streaming:<block>"
+impl std::str::FromStr for Attention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "full" => Ok(Attention::Full),
+            None if s == "streaming" => Ok(Attention::Streaming { block: 64 }),
+            Some(("streaming", block)) => match block.parse() {
+                Ok(block) if block > 0 => Ok(Attention::Streaming { block }),
+                _ => Err(format!("invalid attention block {block}")),
+            },
+            _ => Err(format!("unknown attention {s}")),
+        }
+    }
+}
+
+// What a chat does when the KV cache fills up
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // end the answer there; a prompt that doesn't fit is refused
+    #[default]
+    Stop,
+    // drop the oldest turns of the conversation before encoding it, so that the
+    // answer fits. Only the caller knows the turns (see ChatManager); an answer
+    // that fills the cache anyway stops.
+    TruncateOldest,
+    // keep the newest half of the context, encode it again from position 0
+    // and go on
+    Slide,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "stop" => Ok(OverflowPolicy::Stop),
+            "truncate-oldest" => Ok(OverflowPolicy::TruncateOldest),
+            "slide" => Ok(OverflowPolicy::Slide),
+            _ => Err(format!("unknown overflow policy {s}")),
+        }
+    }
+}
 
 pub struct Llama<T> {
-    vocab: usize,           // vocab size
-    n_layers: usize,        // number of layers
-    n_q_h: usize,           // number of heads for q
-    n_kv_h: usize,          // number of heads for k and v
-    d: usize,               // dimension of hidden states
-    dqkv: usize,            // length of a single q, k, or v vector
-    di: usize,              // dimension of intermediate states
-    eps: f32,               // epsilon for RMS normalization
-    rope_theta: f32,        // rope theta for rope initialization
-    max_seq_len: usize,     // maximum sequence length
-    params: LLamaParams<T>, // trained weights of this model
-    bos_token_id: u32,      // start token id
-    eos_token_id: u32,      // end token id
+    vocab: usize,                      // vocab size
+    n_layers: usize,                   // number of layers
+    n_q_h: usize,                      // number of heads for q
+    n_kv_h: usize,                     // number of heads for k and v
+    d: usize,                          // dimension of hidden states
+    dqkv: usize,                       // length of a single q, k, or v vector
+    di: usize,                         // dimension of intermediate states
+    eps: f32,                          // epsilon for RMS normalization
+    rope_theta: f32,                   // rope theta for rope initialization
+    rope_scaling: Option<RopeScaling>, // long context scaling of the rope frequencies
+    rope_table: OP::RopeTable,         // rope sin/cos of every position
+    ntk_rope: Option<OP::RopeTable>,   // dynamic NTK sin/cos of every position
+    max_position_embeddings: usize,    // context the model was trained on
+    max_seq_len: usize,                // maximum sequence length
+    params: LLamaParams<T>,            // trained weights of this model
+    bos_token_id: u32,                 // start token id
+    eos_token_id: u32,                 // end token id
+    attention: Attention,              // how attention is computed
 }
 
-impl Llama<f32> {
-    pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Self {
-        let config = File::open(model_dir.as_ref().join("config.json")).unwrap();
-        let config: LlamaConfigJson = serde_json::from_reader(config).unwrap();
-        let model_file = std::fs::read(model_dir.as_ref().join("model.safetensors")).unwrap();
-        let safetensor = SafeTensors::deserialize(&model_file).unwrap();
-        let params = LLamaParams::from_safetensors(&safetensor, &config);
+impl<T: Float> Llama<T> {
+    pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Result<Self, LoadError> {
+        Self::from_safetensors_with(model_dir, &LoadOptions::default())
+    }
+
+    // Load a model, storing its weights in the formats chosen by `options`
+    pub fn from_safetensors_with(
+        model_dir: impl AsRef<Path>,
+        options: &LoadOptions,
+    ) -> Result<Self, LoadError> {
+        let config_path = model_dir.as_ref().join("config.json");
+        let config = File::open(&config_path).map_err(|source| LoadError::MissingFile {
+            path: config_path,
+            source,
+        })?;
+        let config: LlamaConfigJson =
+            serde_json::from_reader(BufReader::new(config)).map_err(LoadError::BadConfig)?;
+        let params =
+            LLamaParams::from_model_dir(model_dir.as_ref(), &config)?.into_formats(options);
+        Ok(Self::new(&config, params))
+    }
 
+    #[allow(unused)]
+    pub fn from_gguf(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        Self::from_gguf_with(path, &LoadOptions::default())
+    }
+
+    // Load a single-file GGUF model; its metadata takes the place of config.json
+    pub fn from_gguf_with(
+        path: impl AsRef<Path>,
+        options: &LoadOptions,
+    ) -> Result<Self, LoadError> {
+        let (config, params) = gguf::load(path.as_ref())?;
+        Ok(Self::new(&config, params.into_formats(options)))
+    }
+
+    // Write config.json and model.safetensors to `model_dir`. Each file is
+    // replaced whole, the config last, so the directory can be the one the
+    // model was loaded from.
+    #[allow(unused)]
+    pub fn save_safetensors(&self, model_dir: impl AsRef<Path>) -> Result<(), SaveError> {
+        self.save_safetensors_with(model_dir, &SaveOptions::default())
+    }
+
+    pub fn save_safetensors_with(
+        &self,
+        model_dir: impl AsRef<Path>,
+        options: &SaveOptions,
+    ) -> Result<(), SaveError> {
+        let model_dir = model_dir.as_ref();
+        let dtype = options.dtype.unwrap_or(T::DTYPE);
+        let torch_dtype = torch_dtype_name(dtype).ok_or(SaveError::UnsupportedDtype(dtype))?;
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| SaveError::Io { path, source }
+        };
+        std::fs::create_dir_all(model_dir).map_err(io_error(model_dir))?;
+
+        let config = LlamaConfigJson {
+            bos_token_id: self.bos_token_id,
+            eos_token_id: self.eos_token_id,
+            hidden_size: self.d,
+            intermediate_size: self.di,
+            max_position_embeddings: self.max_position_embeddings,
+            num_attention_heads: self.n_q_h,
+            num_hidden_layers: self.n_layers,
+            num_key_value_heads: self.n_kv_h,
+            vocab_size: self.vocab,
+            rms_norm_eps: self.eps,
+            rope_theta: self.rope_theta,
+            rope_scaling: self.rope_scaling.clone(),
+            torch_dtype: torch_dtype.to_string(),
+            tie_word_embeddings: self.params.is_tied(),
+            head_dim: (self.dqkv != self.d / self.n_q_h).then_some(self.dqkv),
+        };
+        self.params.save_safetensors(
+            &model_dir.join("model.safetensors"),
+            dtype,
+            options.int8_linear,
+        )?;
+        let config = serde_json::to_string_pretty(&config).unwrap();
+        write_atomic(&model_dir.join("config.json"), config.as_bytes())
+    }
+
+    fn new(config: &LlamaConfigJson, params: LLamaParams<T>) -> Self {
+        let max_position_embeddings = config.max_position_embeddings;
+        // the frequencies of sequences up to max_position_embeddings, which
+        // dynamic NTK scaling leaves as they are
+        let (inv_freq, attention_factor) = OP::rope_frequencies(
+            config.head_dim(),
+            config.rope_theta,
+            config.rope_scaling.as_ref(),
+            max_position_embeddings,
+            max_position_embeddings,
+        );
+        // dynamic NTK scaling stretches the context by its factor, and a
+        // sink window cache, which never grows past the trained context,
+        // keeps the plain table
+        let (max_seq_len, ntk_rope) = match config.rope_scaling {
+            Some(ref scaling @ RopeScaling::Dynamic { factor }) => {
+                let max_seq_len = (max_position_embeddings as f32 * factor.max(1.)) as usize;
+                let table = OP::RopeTable::dynamic(
+                    max_seq_len,
+                    config.head_dim(),
+                    config.rope_theta,
+                    scaling,
+                    max_position_embeddings,
+                );
+                (max_seq_len, Some(table))
+            }
+            _ => (max_position_embeddings, None),
+        };
         Self {
             vocab: config.vocab_size,
             n_layers: config.num_hidden_layers,
             n_q_h: config.num_attention_heads,
             n_kv_h: config.num_key_value_heads,
             d: config.hidden_size,
-            dqkv: config.hidden_size / config.num_attention_heads,
+            dqkv: config.head_dim(),
             di: config.intermediate_size,
             eps: config.rms_norm_eps,
             rope_theta: config.rope_theta,
-            max_seq_len: config.max_position_embeddings,
-            params: params,
+            rope_scaling: config.rope_scaling.clone(),
+            // twice the context, so that a sink window cache can slide as far
+            // again before its keys are turned back, see align_keys
+            rope_table: OP::RopeTable::new(
+                2 * max_position_embeddings,
+                &inv_freq,
+                attention_factor,
+            ),
+            ntk_rope,
+            max_position_embeddings,
+            max_seq_len,
+            params,
             bos_token_id: config.bos_token_id,
             eos_token_id: config.eos_token_id,
+            attention: Attention::default(),
+        }
+    }
+
+    pub fn set_attention(&mut self, attention: Attention) {
+        if let Attention::Streaming { block } = attention {
+            assert!(block > 0, "attention blocks must not be empty");
         }
+        self.attention = attention;
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
     }
 
-    pub fn new_cache(&self) -> KVCache<f32> {
+    // Positions `cache` can hold with this model
+    pub fn context_len(&self, cache: &KVCache<T>) -> usize {
+        cache.capacity().min(self.max_seq_len)
+    }
+
+    // Positions a forward pass can add to `cache`. A sink window cache is never
+    // larger than the model's context.
+    fn room(&self, cache: &KVCache<T>) -> usize {
+        cache
+            .room()
+            .saturating_sub(cache.capacity() - self.context_len(cache))
+    }
+
+    pub fn new_cache(&self) -> KVCache<T> {
         KVCache::new(self.n_layers, self.max_seq_len, self.n_kv_h * self.dqkv, 0)
     }
 
-    pub fn forward(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+    // A cache for endless sequences: `sinks` attention sinks, and a window
+    // over the rest of the trained context. None if that leaves the window
+    // fewer than two positions to slide over.
+    pub fn new_sink_window_cache(&self, sinks: usize) -> Option<KVCache<T>> {
+        let window = self.max_position_embeddings.checked_sub(sinks)?;
+        (window >= 2).then(|| {
+            KVCache::with_sink_window(self.n_layers, sinks, window, self.n_kv_h * self.dqkv)
+        })
+    }
+
+    // A pool of up to max_blocks blocks of block_len positions, which the
+    // paged caches of any number of sequences share
+    pub fn new_block_pool(&self, block_len: usize, max_blocks: usize) -> BlockPool<T> {
+        BlockPool::new(
+            self.n_layers,
+            self.n_kv_h * self.dqkv,
+            block_len,
+            max_blocks,
+        )
+    }
+
+    // A cache whose memory grows with its sequence, in blocks from `pool`
+    pub fn new_paged_cache(&self, pool: &BlockPool<T>) -> KVCache<T> {
+        assert!(
+            pool.n_layers() == self.n_layers && pool.dim() == self.n_kv_h * self.dqkv,
+            "the pool's blocks don't match the model"
+        );
+        KVCache::paged(pool, self.max_seq_len)
+    }
+
+    // Returns the f32 logits of the token after the last input token, or
+    // ContextFull, leaving the cache as it was, if the input doesn't fit
+    pub fn forward(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<T>,
+    ) -> Result<Tensor<f32>, ContextFull> {
+        match cache.sink_window() {
+            // a sink window cache takes a prompt longer than its window in pieces
+            Some(SinkWindow { window, .. }) if input.size() > window => {
+                return self.prefill(input.data(), cache, window);
+            }
+            // so does any cache a long prompt fits in whole
+            None if input.size() > PREFILL_CHUNK && input.size() <= self.room(cache) => {
+                return self.prefill(input.data(), cache, PREFILL_CHUNK);
+            }
+            _ => {}
+        }
+        let residual = self.run_layers(input, cache)?;
+        Ok(self.logits(&residual))
+    }
+
+    // Feeds `tokens` through the cache in chunks of at most `max_chunk`
+    // tokens, so that no buffer grows with the whole prompt. Returns the same
+    // logits as a single forward over all of them.
+    pub fn prefill(
+        &self,
+        tokens: &[u32],
+        cache: &mut KVCache<T>,
+        max_chunk: usize,
+    ) -> Result<Tensor<f32>, ContextFull> {
+        let mut prefill = Prefill::new(tokens.to_vec(), max_chunk);
+        loop {
+            if let Some(logits) = prefill.step(self, cache)? {
+                return Ok(logits);
+            }
+        }
+    }
+
+    // Runs the layers over `input` after what is in the cache, appending to
+    // the cache, and returns the residual stream (seq, d)
+    fn run_layers(
+        &self,
+        input: &Tensor<u32>,
+        cache: &mut KVCache<T>,
+    ) -> Result<Tensor<T>, ContextFull> {
         let seq_len = input.size();
+        if seq_len > self.room(cache) {
+            return Err(ContextFull {
+                cached: cache.len(),
+                requested: seq_len,
+                capacity: self.context_len(cache).min(cache.len() + self.room(cache)),
+            });
+        }
+        cache.make_room(seq_len);
         let past_seq_len = cache.len();
+        let rope_shift = self.align_keys(cache, seq_len);
+        let rope_table = match (&self.ntk_rope, cache.sink_window()) {
+            (Some(table), None) => table,
+            _ => &self.rope_table,
+        };
         cache.increment(seq_len);
         let total_seq_len = past_seq_len + seq_len;
         let n_groups = self.n_q_h / self.n_kv_h;
 
         // Some pre-allocated buffers that will be reused
-        let mut residual = Tensor::<f32>::default(&vec![seq_len, self.d]);
-        let mut hidden_states = Tensor::<f32>::default(&vec![seq_len, self.d]);
-        let mut q_buf = Tensor::<f32>::default(&vec![seq_len, self.n_q_h * self.dqkv]);
-        let mut att_scores =
-            Tensor::<f32>::default(&vec![self.n_kv_h, n_groups, seq_len, total_seq_len]);
-        let mut gate_buf = Tensor::<f32>::default(&vec![seq_len, self.di]);
-        let mut up_buf = Tensor::<f32>::default(&vec![seq_len, self.di]);
+        let mut residual = Tensor::<T>::default(&[seq_len, self.d]);
+        let mut hidden_states = Tensor::<T>::default(&[seq_len, self.d]);
+        let mut q_buf = Tensor::<T>::default(&[seq_len, self.n_q_h * self.dqkv]);
+        // the heads may be wider or narrower than d in total
+        let mut attn_buf = Tensor::<T>::default(&[seq_len, self.n_q_h * self.dqkv]);
+        let mut gate_buf = Tensor::<T>::default(&[seq_len, self.di]);
+        let mut up_buf = Tensor::<T>::default(&[seq_len, self.di]);
+        // new K and V on their way into a sink window or a paged cache
+        let in_place = cache.sink_window().is_none() && !cache.is_paged();
+        let kv_len = if in_place { 0 } else { seq_len };
+        let mut k_buf = Tensor::<T>::default(&[kv_len, self.n_kv_h * self.dqkv]);
+        let mut v_buf = Tensor::<T>::default(&[kv_len, self.n_kv_h * self.dqkv]);
 
         // Computation Starts Here
         // Embedding lookup
@@ -84,49 +386,80 @@ impl Llama<f32> {
                 self.eps,
             );
 
-            let q = (&mut q_buf).reshape(&vec![seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
-            let k = &mut cache.k_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
-            let v = &mut cache.v_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
+            let q = q_buf.reshape(&[seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
+            let (k, v) = match in_place {
+                false => (&mut k_buf, &mut v_buf),
+                true => (
+                    &mut cache.k_cache(layer, past_seq_len), // (seq, n_kv_h * dqkv)
+                    &mut cache.v_cache(layer, past_seq_len), // (seq, n_kv_h * dqkv)
+                ),
+            };
 
             // 线性投影
-            OP::matmul_transb(q, 0., &hidden_states, &self.params.wq[layer], 1.0); // Q = XW_Q
-            OP::matmul_transb(k, 0., &hidden_states, &self.params.wk[layer], 1.0); // K = XW_K
-            OP::matmul_transb(v, 0., &hidden_states, &self.params.wv[layer], 1.0); // v = XW_V
-            OP::rope(
-                q.reshape(&vec![seq_len, self.n_q_h, self.dqkv]),
-                past_seq_len,
-                self.rope_theta,
+            OP::linear(q, 0., &hidden_states, &self.params.wq[layer], 1.0); // Q = XW_Q
+            OP::linear(k, 0., &hidden_states, &self.params.wk[layer], 1.0); // K = XW_K
+            OP::linear(v, 0., &hidden_states, &self.params.wv[layer], 1.0); // v = XW_V
+            OP::rope_cached(
+                q.reshape(&[seq_len, self.n_q_h, self.dqkv]),
+                past_seq_len + rope_shift,
+                rope_table,
             );
-            OP::rope(
-                k.reshape(&vec![seq_len, self.n_kv_h, self.dqkv]),
-                past_seq_len,
-                self.rope_theta,
+            OP::rope_cached(
+                k.reshape(&[seq_len, self.n_kv_h, self.dqkv]),
+                past_seq_len + rope_shift,
+                rope_table,
             );
+            if !in_place {
+                let k = k.reshape(&[seq_len, self.n_kv_h * self.dqkv]);
+                cache.store(layer, past_seq_len, k, v);
+            }
 
-            let full_k = &mut cache.k_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
-            let full_v = &mut cache.v_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
+            // (total_seq, n_kv_h * dqkv), read through the block table of a
+            // paged cache or the ring of a sink window
+            let (full_k, full_v) = cache.rows(layer);
+            let sink_keys: Vec<T>;
+            let full_k = match cache.sink_window() {
+                Some(SinkWindow { sinks, .. }) if rope_shift > 0 => {
+                    let rows = (0..sinks.min(total_seq_len)).flat_map(|pos| full_k.row(pos));
+                    let mut keys: Vec<T> = rows.copied().collect();
+                    OP::rope_shift(&mut keys, self.dqkv, rope_shift as isize, &self.rope_table);
+                    sink_keys = keys;
+                    full_k.with_sinks(&sink_keys)
+                }
+                _ => full_k,
+            };
 
             // 计算多头注意力
-            self_attention(
-                &mut hidden_states,
-                &mut att_scores,
-                q,
-                full_k,
-                full_v,
-                self.n_kv_h,
-                n_groups,
-                seq_len,
-                total_seq_len,
-                self.dqkv,
-            );
+            match self.attention {
+                Attention::Full => self_attention(
+                    &mut attn_buf,
+                    // only full attention stores the scores, which grow with
+                    // seq_len * total_seq_len
+                    &mut Tensor::default(&[self.n_kv_h, n_groups, seq_len, total_seq_len]),
+                    q,
+                    &full_k,
+                    &full_v,
+                    self.n_kv_h,
+                    n_groups,
+                    seq_len,
+                    total_seq_len,
+                    self.dqkv,
+                ),
+                Attention::Streaming { block } => streaming_attention(
+                    &mut attn_buf,
+                    q,
+                    &full_k,
+                    &full_v,
+                    self.n_kv_h,
+                    n_groups,
+                    seq_len,
+                    total_seq_len,
+                    self.dqkv,
+                    block,
+                ),
+            }
 
-            OP::matmul_transb(
-                &mut residual,
-                1.0,
-                &hidden_states,
-                &self.params.wo[layer],
-                1.0,
-            );
+            OP::linear(&mut residual, 1.0, &attn_buf, &self.params.wo[layer], 1.0);
 
             mlp(
                 &mut residual,
@@ -141,11 +474,41 @@ impl Llama<f32> {
             );
         }
 
+        Ok(residual)
+    }
+
+    // A sink window cache keeps each key after the sinks rotated for its
+    // position plus cache.shift(), so that a slide leaves the keys of the
+    // window as they are: they keep their distances to each other and to new
+    // queries, which are rotated the same. The sinks, which don't move, keep
+    // the rotation of their position, and a copy of them is turned by the shift
+    // on the way into attention. Once the rope table would run out, the keys
+    // of the window are turned back and the shift starts over. Returns the
+    // shift new queries and keys are rotated with.
+    fn align_keys(&self, cache: &mut KVCache<T>, seq_len: usize) -> usize {
+        let (len, shift) = (cache.len(), cache.shift());
+        if let Some(SinkWindow { sinks, .. }) = cache.sink_window() {
+            if shift + len + seq_len > self.rope_table.max_seq_len() {
+                for layer in 0..self.n_layers {
+                    for pos in sinks..len {
+                        let key = cache.key_mut(layer, pos);
+                        OP::rope_shift(key, self.dqkv, -(shift as isize), &self.rope_table);
+                    }
+                }
+                cache.clear_shift();
+            }
+        }
+        cache.shift()
+    }
+
+    // The f32 logits of the token after the last row of the residual stream
+    fn logits(&self, residual: &Tensor<T>) -> Tensor<f32> {
+        let seq_len = residual.shape()[0];
         // No matter what seq_len, the output is always a 1D vector of length vocab,
         // which contains the probabilities for the next token.
-        let mut logits = Tensor::<f32>::default(&vec![1, self.vocab]);
-        let mut hidden_states = hidden_states.slice((seq_len - 1) * self.d, &vec![1, self.d]);
-        let residual = residual.slice((seq_len - 1) * self.d, &vec![self.d]);
+        let mut logits = Tensor::<T>::default(&[1, self.vocab]);
+        let mut hidden_states = Tensor::<T>::default(&[1, self.d]);
+        let residual = residual.slice((seq_len - 1) * self.d, &[self.d]);
 
         OP::rms_norm(
             &mut hidden_states,
@@ -154,9 +517,10 @@ impl Llama<f32> {
             self.eps,
         );
 
-        OP::matmul_transb(&mut logits, 0., &hidden_states, &self.params.lm_head, 1.0);
+        OP::linear(&mut logits, 0., &hidden_states, &self.params.lm_head, 1.0);
 
-        logits
+        let logits = logits.data().iter().map(|x| x.to_f32()).collect();
+        Tensor::<f32>::new(logits, &[1, self.vocab])
     }
 
     pub fn generate(
@@ -169,29 +533,25 @@ impl Llama<f32> {
     ) -> Vec<u32> {
         let mut result = Vec::<u32>::from(token_ids);
         result.push(self.bos_token_id);
-        let mut cache: KVCache<f32> =
-            KVCache::new(self.n_layers, self.max_seq_len, self.n_kv_h * self.dqkv, 0);
-        let mut input = Tensor::<u32>::new(token_ids.to_vec(), &vec![token_ids.len()]);
-
-        // 按照最大长度生成结果
-        for _ in 0..max_len {
-            // 前向传播，获取 logits
-            let logits = self.forward(&input, &mut cache);
-
-            let next_token = OP::random_sample(&logits, top_p, top_k, temperature);
-
-            if next_token == self.eos_token_id {
-                break;
-            }
-            result.push(next_token);
-
-            input = Tensor::<u32>::new(vec![next_token], &vec![1]);
-        }
-
+        let mut cache = self.new_cache();
+        // a prompt longer than the context gives no tokens
+        let generated = self.sample(
+            token_ids,
+            max_len,
+            top_p,
+            top_k,
+            temperature,
+            OverflowPolicy::Stop,
+            &mut cache,
+        );
+        result.extend(generated.unwrap_or_default());
         result
     }
 
     // 回答问题 添加cache
+    // Returns ContextFull if the prompt doesn't fit, or, under Slide, if even
+    // half of the context doesn't
+    #[allow(clippy::too_many_arguments)]
     pub fn answer(
         &self,
         token_ids: &[u32],
@@ -199,37 +559,254 @@ impl Llama<f32> {
         top_p: f32,
         top_k: u32,
         temperature: f32,
-        kv_cache: &mut KVCache<f32>,
-    ) -> Vec<u32> {
+        overflow: OverflowPolicy,
+        kv_cache: &mut KVCache<T>,
+    ) -> Result<Vec<u32>, ContextFull> {
         let mut result = Vec::<u32>::from(token_ids);
         result.push(self.bos_token_id);
-        let mut input = Tensor::<u32>::new(token_ids.to_vec(), &vec![token_ids.len()]);
+        result.extend(self.sample(
+            token_ids,
+            max_len,
+            top_p,
+            top_k,
+            temperature,
+            overflow,
+            kv_cache,
+        )?);
+        Ok(result)
+    }
+
+    // Samples up to max_len tokens after `token_ids`, which are fed after what
+    // is in `cache`, stopping at the end token. When the cache is full, follows
+    // the overflow policy; Slide keeps a window of the tokens fed in this call.
+    #[allow(clippy::too_many_arguments)]
+    fn sample(
+        &self,
+        token_ids: &[u32],
+        max_len: usize,
+        top_p: f32,
+        top_k: u32,
+        temperature: f32,
+        overflow: OverflowPolicy,
+        cache: &mut KVCache<T>,
+    ) -> Result<Vec<u32>, ContextFull> {
+        let mut generated = Vec::new();
+        let mut window = Vec::new(); // tokens fed so far
+        let mut input = token_ids.to_vec();
 
         // 按照最大长度生成结果
-        for _ in 0..max_len {
+        for step in 0..max_len {
             // 前向传播，获取 logits
-            let logits = self.forward(&input, kv_cache);
+            let logits = match self.forward(&Tensor::new(input.clone(), &[input.len()]), cache) {
+                Ok(logits) => logits,
+                Err(_) if overflow == OverflowPolicy::Slide => {
+                    window.extend(&input);
+                    let keep = (self.context_len(cache) / 2).max(1);
+                    window.drain(..window.len().saturating_sub(keep));
+                    cache.truncate(0);
+                    input = std::mem::take(&mut window);
+                    let input_tensor = Tensor::new(input.clone(), &[input.len()]);
+                    // half of the context fits, unless a shared block pool ran dry
+                    self.forward(&input_tensor, cache)?
+                }
+                Err(full) if step == 0 => return Err(full),
+                Err(_) => break,
+            };
+            window.append(&mut input);
 
             let next_token = OP::random_sample(&logits, top_p, top_k, temperature);
 
             if next_token == self.eos_token_id {
                 break;
             }
+            generated.push(next_token);
+            input = vec![next_token];
+        }
+
+        Ok(generated)
+    }
+}
 
-            result.push(next_token);
-            input = Tensor::<u32>::new(vec![next_token], &vec![1]);
+// A prompt fed through a KV cache one chunk at a time. Between two steps the
+// caller is free to run other work, such as decode steps of other sequences
+// on their own caches.
+pub struct Prefill {
+    tokens: Vec<u32>,
+    pos: usize, // tokens already in the cache
+    max_chunk: usize,
+}
+
+impl Prefill {
+    pub fn new(tokens: Vec<u32>, max_chunk: usize) -> Self {
+        assert!(!tokens.is_empty(), "nothing to prefill");
+        assert!(max_chunk > 0, "prefill chunks must not be empty");
+        Prefill {
+            tokens,
+            pos: 0,
+            max_chunk,
         }
+    }
 
-        result
+    // Tokens not fed yet
+    #[allow(unused)]
+    pub fn remaining(&self) -> usize {
+        self.tokens.len() - self.pos
     }
+
+    // Feeds the next chunk. Returns the logits of the token after the prompt
+    // once the last chunk has been fed, and None before that. A chunk that
+    // doesn't fit in the cache is not consumed.
+    pub fn step<T: Float>(
+        &mut self,
+        model: &Llama<T>,
+        cache: &mut KVCache<T>,
+    ) -> Result<Option<Tensor<f32>>, ContextFull> {
+        assert!(self.pos < self.tokens.len(), "prefill already finished");
+        let end = (self.pos + self.max_chunk).min(self.tokens.len());
+        let chunk = self.tokens[self.pos..end].to_vec();
+        let input = Tensor::<u32>::new(chunk, &[end - self.pos]);
+        let residual = model.run_layers(&input, cache)?;
+        self.pos = end;
+        // only the last chunk needs the lm_head
+        Ok((end == self.tokens.len()).then(|| model.logits(&residual)))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn self_attention<T: Float>(
+    hidden_states: &mut Tensor<T>, // (seq, n_kv_h * n_groups * dqkv)
+    att_scores: &mut Tensor<T>,    // (n_kv_h, n_groups, seq, total_seq)
+    q: &Tensor<T>,                 // (seq, n_kv_h * n_groups * dqkv)
+    k: &Rows<T>,                   // total_seq rows of n_kv_h * dqkv
+    v: &Rows<T>,                   // total_seq rows of n_kv_h * dqkv
+    n_kv_h: usize,
+    n_groups: usize,
+    seq_len: usize,
+    total_seq_len: usize,
+    dqkv: usize,
+) {
+    let n_q_h = n_kv_h * n_groups;
+    let q_stride = n_q_h * dqkv; // Q 每个 seq 位置的总维度
+    let scale = 1.0 / (dqkv as f32).sqrt();
+    let q = q.data();
+    let kernels = simd::kernels();
+    // q_pos 只能看到前 total_seq_len - seq_len + q_pos + 1 个位置
+    let boundary = |q_pos: usize| total_seq_len - seq_len + q_pos + 1;
+
+    OP::install(|| {
+        // 计算注意力分数并 softmax：每个 Q 头写自己的 (seq, total_seq) 分数块
+        let scores = att_scores.data_mut_owned();
+        (scores.par_chunks_mut(seq_len * total_seq_len).enumerate()).for_each(
+            |(q_head, scores)| {
+                let kv_head = q_head / n_groups;
+                // half precision rows are widened into these, one head at a time
+                let (mut q_buf, mut k_buf) = (Vec::with_capacity(dqkv), Vec::with_capacity(dqkv));
+                for (q_pos, row) in scores.chunks_exact_mut(total_seq_len).enumerate() {
+                    let q_i =
+                        OP::to_f32_in(&q[q_pos * q_stride + q_head * dqkv..][..dqkv], &mut q_buf);
+                    for (k_pos, score) in row[..boundary(q_pos)].iter_mut().enumerate() {
+                        let k_j =
+                            OP::to_f32_in(&k.row(k_pos)[kv_head * dqkv..][..dqkv], &mut k_buf);
+                        *score = T::from_f32(kernels.dot(q_i, k_j) * scale);
+                    }
+                    masked_softmax_row(row, boundary(q_pos));
+                }
+            },
+        );
+
+        // 加权求和（Attn @ V）：每个 (q_pos, Q 头) 写 hidden_states 中自己的 dqkv 段
+        let scores = att_scores.data();
+        let hidden = hidden_states.data_mut_owned();
+        (hidden.par_chunks_mut(dqkv).enumerate()).for_each_init(
+            // the sum and a widened value row, reused across a task's heads
+            || (vec![0f32; dqkv], Vec::with_capacity(dqkv)),
+            |(sum, v_buf), (i, out)| {
+                let (q_pos, q_head) = (i / n_q_h, i % n_q_h);
+                let kv_head = q_head / n_groups;
+                let p = &scores[(q_head * seq_len + q_pos) * total_seq_len..][..boundary(q_pos)];
+                sum.fill(0.);
+                for (k_pos, p) in p.iter().enumerate() {
+                    let v_j = OP::to_f32_in(&v.row(k_pos)[kv_head * dqkv..][..dqkv], v_buf);
+                    kernels.axpy(sum, p.to_f32(), v_j);
+                }
+                out.iter_mut()
+                    .zip(sum.iter())
+                    .for_each(|(o, &s)| *o = T::from_f32(s));
+            },
+        );
+    });
 }
 
-fn self_attention(
-    hidden_states: &mut Tensor<f32>, // (seq, n_kv_h * n_groups * dqkv)
-    att_scores: &mut Tensor<f32>,    // (n_kv_h, n_groups, seq, total_seq)
-    q: &Tensor<f32>,                 // (seq, n_kv_h * n_groups * dqkv)
-    k: &Tensor<f32>,                 // (total_seq, n_kv_h * dqkv)
-    v: &Tensor<f32>,                 // (total_seq, n_kv_h * dqkv)
+// The same attention as self_attention, computed per (q_pos, q head) over blocks
+// of `block` keys with an online softmax: the running max m, the running sum l
+// and the unnormalized output acc are rescaled by exp(m_old - m_new) whenever a
+// block raises the max. Only a block of scores is alive at a time.
+#[allow(clippy::too_many_arguments)]
+fn streaming_attention<T: Float>(
+    hidden_states: &mut Tensor<T>, // (seq, n_kv_h * n_groups * dqkv)
+    q: &Tensor<T>,                 // (seq, n_kv_h * n_groups * dqkv)
+    k: &Rows<T>,                   // total_seq rows of n_kv_h * dqkv
+    v: &Rows<T>,                   // total_seq rows of n_kv_h * dqkv
+    n_kv_h: usize,
+    n_groups: usize,
+    seq_len: usize,
+    total_seq_len: usize,
+    dqkv: usize,
+    block: usize,
+) {
+    let n_q_h = n_kv_h * n_groups;
+    let q_stride = n_q_h * dqkv;
+    let scale = 1.0 / (dqkv as f32).sqrt();
+    let q = q.data();
+    let kernels = simd::kernels();
+
+    OP::install(|| {
+        let hidden = unsafe { hidden_states.data_mut() };
+        (hidden.par_chunks_mut(dqkv).enumerate()).for_each(|(i, out)| {
+            let (q_pos, q_head) = (i / n_q_h, i % n_q_h);
+            let kv_head = q_head / n_groups;
+            let boundary = total_seq_len - seq_len + q_pos + 1;
+            let q_i = OP::to_f32(&q[q_pos * q_stride + q_head * dqkv..][..dqkv]);
+            let mut k_buf = Vec::with_capacity(dqkv); // widened key rows
+            let mut scores = vec![0f32; block.min(boundary)];
+            let mut acc = vec![0f32; dqkv];
+            let (mut m, mut l) = (f32::NEG_INFINITY, 0f32);
+            for start in (0..boundary).step_by(block) {
+                let keys = start..(start + block).min(boundary);
+                let scores = &mut scores[..keys.len()];
+                for (score, k_pos) in scores.iter_mut().zip(keys.clone()) {
+                    let k_j = OP::to_f32_in(&k.row(k_pos)[kv_head * dqkv..][..dqkv], &mut k_buf);
+                    *score = kernels.dot(&q_i, k_j) * scale;
+                }
+                let m_new = m.max(kernels.max(scores));
+                // exp(-inf) = 0 on the first block, when acc and l are still 0
+                let correction = (m - m_new).exp();
+                l = l * correction + kernels.exp_sum(scores, m_new);
+                kernels.scale(&mut acc, correction);
+                for (p, k_pos) in scores.iter().zip(keys) {
+                    let v_j = &v.row(k_pos)[kv_head * dqkv..][..dqkv];
+                    for (a, x) in acc.iter_mut().zip(v_j) {
+                        *a += p * x.to_f32();
+                    }
+                }
+                m = m_new;
+            }
+            out.iter_mut()
+                .zip(acc)
+                .for_each(|(o, a)| *o = T::from_f32(a / l));
+        });
+    });
+}
+
+// The sequential loop over heads self_attention replaced, as a reference
+#[cfg(test)]
+#[allow(clippy::too_many_arguments)]
+fn self_attention_reference<T: Float>(
+    hidden_states: &mut Tensor<T>, // (seq, n_kv_h * n_groups * dqkv)
+    att_scores: &mut Tensor<T>,    // (n_kv_h, n_groups, seq, total_seq)
+    q: &Tensor<T>,                 // (seq, n_kv_h * n_groups * dqkv)
+    k: &Tensor<T>,                 // (total_seq, n_kv_h * dqkv)
+    v: &Tensor<T>,                 // (total_seq, n_kv_h * dqkv)
     n_kv_h: usize,
     n_groups: usize,
     seq_len: usize,
@@ -239,7 +816,7 @@ fn self_attention(
     // 计算注意力分数
     for kv_head in 0..n_kv_h {
         for group in 0..n_groups {
-            let mut attn_data = unsafe { att_scores.data_mut() };
+            let attn_data = unsafe { att_scores.data_mut() };
             let q_head = kv_head * n_groups + group; // 当前 Q 头索引
             let q_stride = n_kv_h * n_groups * dqkv; // Q 每个 seq 位置的总维度
             let k_stride = n_kv_h * dqkv; // K 每个 seq 位置的总维度
@@ -250,8 +827,8 @@ fn self_attention(
                     // Q[q_pos, q_head * dqkv + d] & K[k_pos, kv_head * dqkv + d]
                     let score = (0..dqkv)
                         .map(|d| {
-                            q.data()[q_pos * q_stride + q_head * dqkv + d]
-                                * k.data()[k_pos * k_stride + kv_head * dqkv + d]
+                            q.data()[q_pos * q_stride + q_head * dqkv + d].to_f32()
+                                * k.data()[k_pos * k_stride + kv_head * dqkv + d].to_f32()
                         })
                         .sum::<f32>()
                         * (1.0 / (dqkv as f32).sqrt());
@@ -261,13 +838,13 @@ fn self_attention(
                         + group * seq_len * total_seq_len
                         + q_pos * total_seq_len
                         + k_pos;
-                    attn_data[attn_idx] = score;
+                    attn_data[attn_idx] = T::from_f32(score);
                 }
             }
         }
     }
 
-    masked_softmax(att_scores);
+    OP::masked_softmax(att_scores);
 
     // 加权求和（Attn @ V）
     for kv_head in 0..n_kv_h {
@@ -275,7 +852,7 @@ fn self_attention(
             let q_head = kv_head * n_groups + group;
             let v_stride = n_kv_h * dqkv; // V 每个 seq 位置的总维度
             let h_stride = n_kv_h * n_groups * dqkv; // hidden_states 的总维度
-            let mut hidden_data = unsafe { hidden_states.data_mut() };
+            let hidden_data = unsafe { hidden_states.data_mut() };
 
             for q_pos in 0..seq_len {
                 for d in 0..dqkv {
@@ -288,35 +865,36 @@ fn self_attention(
                                 + group * seq_len * total_seq_len
                                 + q_pos * total_seq_len
                                 + k_pos;
-                            att_scores.data()[attn_idx]
-                                * v.data()[k_pos * v_stride + kv_head * dqkv + d]
+                            att_scores.data()[attn_idx].to_f32()
+                                * v.data()[k_pos * v_stride + kv_head * dqkv + d].to_f32()
                         })
                         .sum::<f32>();
 
                     // 存入 hidden_states：[q_pos][q_head * dqkv + d]
                     let h_idx = q_pos * h_stride + q_head * dqkv + d;
-                    hidden_data[h_idx] = value;
+                    hidden_data[h_idx] = T::from_f32(value);
                 }
             }
         }
     }
 }
-fn mlp(
-    residual: &mut Tensor<f32>,
-    hidden_states: &mut Tensor<f32>,
-    gate: &mut Tensor<f32>,
-    up: &mut Tensor<f32>,
-    w_up: &Tensor<f32>,
-    w_down: &Tensor<f32>,
-    w_gate: &Tensor<f32>,
-    rms_w: &Tensor<f32>,
+#[allow(clippy::too_many_arguments)]
+fn mlp<T: Float>(
+    residual: &mut Tensor<T>,
+    hidden_states: &mut Tensor<T>,
+    gate: &mut Tensor<T>,
+    up: &mut Tensor<T>,
+    w_up: &Weight<T>,
+    w_down: &Weight<T>,
+    w_gate: &Weight<T>,
+    rms_w: &Tensor<T>,
     eps: f32,
 ) {
     rms_norm(hidden_states, residual, rms_w, eps); // hidden = rms_norm(residual)
-    matmul_transb(gate, 0.0, hidden_states, w_gate, 1.0); // gate = hidden @ gate_weight.T
-    matmul_transb(up, 0.0, hidden_states, w_up, 1.0); // up = hidden @ up_weight.T
+    linear(gate, 0.0, hidden_states, w_gate, 1.0); // gate = hidden @ gate_weight.T
+    linear(up, 0.0, hidden_states, w_up, 1.0); // up = hidden @ up_weight.T
     swiglu(up, gate);
-    matmul_transb(residual, 1.0, up, w_down, 1.0);
+    linear(residual, 1.0, up, w_down, 1.0);
 }
 
 #[test]
@@ -324,14 +902,23 @@ pub fn test_mlp() {
     let seq_len = 4;
     let d = 2;
     let di = 3;
-    let mut residual = Tensor::<f32>::new(vec![1., 1., 1., 1., 1., 1., 1., 1.], &vec![seq_len, d]);
-    let mut hidden_states = Tensor::<f32>::default(&vec![seq_len, d]);
-    let mut gate_buf = Tensor::<f32>::default(&vec![seq_len, di]);
-    let mut up_buf = Tensor::<f32>::default(&vec![seq_len, di]);
-    let w_up = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![di, d]);
-    let w_down = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![d, di]);
-    let w_gate = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![di, d]);
-    let rms_w = Tensor::<f32>::new(vec![1., 1.], &vec![d]);
+    let mut residual = Tensor::<f32>::new(vec![1., 1., 1., 1., 1., 1., 1., 1.], &[seq_len, d]);
+    let mut hidden_states = Tensor::<f32>::default(&[seq_len, d]);
+    let mut gate_buf = Tensor::<f32>::default(&[seq_len, di]);
+    let mut up_buf = Tensor::<f32>::default(&[seq_len, di]);
+    let w_up = Weight::Dense(Tensor::<f32>::new(
+        vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+        &[di, d],
+    ));
+    let w_down = Weight::Dense(Tensor::<f32>::new(
+        vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+        &[d, di],
+    ));
+    let w_gate = Weight::Dense(Tensor::<f32>::new(
+        vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+        &[di, d],
+    ));
+    let rms_w = Tensor::<f32>::new(vec![1., 1.], &[d]);
     let eps = 1e-6;
     mlp(
         &mut residual,
@@ -351,19 +938,390 @@ pub fn test_mlp() {
                 1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964,
                 1.7290739
             ],
-            &vec![seq_len, d]
+            &[seq_len, d]
         ),
         1e-3
     ))
 }
 
+#[test]
+pub fn test_self_attention() {
+    use crate::test_util::values;
+    // 3 new positions after 4 cached ones, 2 kv heads shared by 3 q heads each
+    let (n_kv_h, n_groups, seq_len, total_seq_len, dqkv) = (2, 3, 3, 7, 5);
+    let q = Tensor::<f32>::new(values(seq_len * 30, 0.37), &[seq_len, 30]);
+    let k = Tensor::<f32>::new(values(total_seq_len * 10, 0.91), &[total_seq_len, 10]);
+    let v = Tensor::<f32>::new(values(total_seq_len * 10, 0.13), &[total_seq_len, 10]);
+    let scores_shape = [n_kv_h, n_groups, seq_len, total_seq_len];
+
+    let mut hidden_states = Tensor::<f32>::default(&[seq_len, 30]);
+    let mut att_scores = Tensor::<f32>::default(&scores_shape);
+    self_attention(
+        &mut hidden_states,
+        &mut att_scores,
+        &q,
+        &Rows::contiguous(&k),
+        &Rows::contiguous(&v),
+        n_kv_h,
+        n_groups,
+        seq_len,
+        total_seq_len,
+        dqkv,
+    );
+    let mut expected_hidden = Tensor::<f32>::default(&[seq_len, 30]);
+    let mut expected_scores = Tensor::<f32>::default(&scores_shape);
+    self_attention_reference(
+        &mut expected_hidden,
+        &mut expected_scores,
+        &q,
+        &k,
+        &v,
+        n_kv_h,
+        n_groups,
+        seq_len,
+        total_seq_len,
+        dqkv,
+    );
+
+    assert!(att_scores.close_to(&expected_scores, 1e-5));
+    assert!(hidden_states.close_to(&expected_hidden, 1e-5));
+
+    // the same keys and values read through blocks of 3 rows, the last partly filled
+    let blocks = |t: &Tensor<f32>| {
+        let mut data = t.data().to_vec();
+        data.resize(9 * 10, f32::NAN);
+        data.chunks(30).map(|b| b.to_vec()).collect::<Vec<_>>()
+    };
+    let (k_blocks, v_blocks) = (blocks(&k), blocks(&v));
+    fn paged(blocks: &[Vec<f32>], len: usize) -> Rows<'_, f32> {
+        Rows::new(blocks.iter().map(|b| &b[..]).collect(), 3, 10, len)
+    }
+    let mut paged_hidden = Tensor::<f32>::default(&[seq_len, 30]);
+    self_attention(
+        &mut paged_hidden,
+        &mut att_scores,
+        &q,
+        &paged(&k_blocks, total_seq_len),
+        &paged(&v_blocks, total_seq_len),
+        n_kv_h,
+        n_groups,
+        seq_len,
+        total_seq_len,
+        dqkv,
+    );
+    assert_eq!(paged_hidden.data(), hidden_states.data());
+}
+
+#[test]
+pub fn test_streaming_attention() {
+    use crate::test_util::values;
+    let (n_kv_h, n_groups, dqkv) = (2, 3, 5);
+    // scores spread wide enough for the running maximum to move
+    let values = |len, seed| -> Vec<f32> { values(len, seed).iter().map(|x| x * 3.).collect() };
+    // a prefill, then new positions after cached ones
+    for (seq_len, total_seq_len) in [(9, 9), (3, 7), (1, 10)] {
+        let q = Tensor::<f32>::new(values(seq_len * 30, 0.37), &[seq_len, 30]);
+        let k = Tensor::<f32>::new(values(total_seq_len * 10, 0.91), &[total_seq_len, 10]);
+        let v = Tensor::<f32>::new(values(total_seq_len * 10, 0.13), &[total_seq_len, 10]);
+        let mut expected = Tensor::<f32>::default(&[seq_len, 30]);
+        let mut att_scores = Tensor::<f32>::default(&[n_kv_h, n_groups, seq_len, total_seq_len]);
+        let (k, v) = (Rows::contiguous(&k), Rows::contiguous(&v));
+        self_attention(
+            &mut expected,
+            &mut att_scores,
+            &q,
+            &k,
+            &v,
+            n_kv_h,
+            n_groups,
+            seq_len,
+            total_seq_len,
+            dqkv,
+        );
+        // blocks that divide the keys, leave a partial block, or hold them all
+        for block in [1, 2, 3, 4, 64] {
+            let mut hidden_states = Tensor::<f32>::default(&[seq_len, 30]);
+            streaming_attention(
+                &mut hidden_states,
+                &q,
+                &k,
+                &v,
+                n_kv_h,
+                n_groups,
+                seq_len,
+                total_seq_len,
+                dqkv,
+                block,
+            );
+            assert!(hidden_states.close_to(&expected, 1e-5), "block {block}");
+        }
+    }
+}
+
+#[test]
+pub fn test_chunked_prefill() {
+    use crate::test_util::{story_model, test_tokens};
+    let model = story_model();
+    let prompt = test_tokens(13);
+    let expected = model
+        .forward(
+            &Tensor::<u32>::new(prompt.clone(), &[prompt.len()]),
+            &mut model.new_cache(),
+        )
+        .unwrap();
+    // every position sees the same keys whatever the chunking, so the logits are equal
+    for max_chunk in [1, 4, 5, 13, 100] {
+        let mut cache = model.new_cache();
+        let logits = model.prefill(&prompt, &mut cache, max_chunk).unwrap();
+        assert_eq!(logits.data(), expected.data(), "chunks of {max_chunk}");
+        assert_eq!(cache.len(), prompt.len());
+    }
+
+    // decode steps of another sequence in between don't disturb the prompt
+    let next = Tensor::<u32>::new(vec![42], &[1]);
+    let mut other_cache = model.new_cache();
+    let mut prefill = Prefill::new(prompt.clone(), 4);
+    let mut cache = model.new_cache();
+    let logits = loop {
+        model.forward(&next, &mut other_cache).unwrap();
+        if let Some(logits) = prefill.step(&model, &mut cache).unwrap() {
+            break logits;
+        }
+    };
+    assert_eq!(other_cache.len(), 4);
+    assert_eq!(prefill.remaining(), 0);
+    assert_eq!(logits.data(), expected.data());
+
+    // forward feeds a prompt longer than PREFILL_CHUNK in chunks, to the same logits
+    let prompt = test_tokens(150);
+    let mut cache = model.new_cache();
+    let logits = model
+        .forward(&Tensor::<u32>::new(prompt.clone(), &[150]), &mut cache)
+        .unwrap();
+    let expected = model.prefill(&prompt, &mut model.new_cache(), 150).unwrap();
+    assert_eq!(logits.data(), expected.data());
+    assert_eq!(cache.len(), 150);
+}
+
+#[test]
+pub fn test_context_full() {
+    use crate::test_util::{story_model, test_tokens};
+    let model = story_model();
+    let mut cache = KVCache::new(model.n_layers, 16, model.n_kv_h * model.dqkv, 0);
+    let prompt = test_tokens(12);
+    model
+        .forward(&Tensor::new(prompt.clone(), &[12]), &mut cache)
+        .unwrap();
+    let err = model
+        .forward(&Tensor::new(vec![5; 5], &[5]), &mut cache)
+        .err();
+    let full = ContextFull {
+        cached: 12,
+        requested: 5,
+        capacity: 16,
+    };
+    assert_eq!(err, Some(full));
+    assert_eq!(cache.len(), 12);
+
+    // the prompt and 4 more tokens fit, each of which gives one token
+    cache.truncate(0);
+    let answer = |overflow, prompt: &[u32], cache: &mut KVCache<f32>| {
+        model.answer(prompt, 30, 1., 1, 1., overflow, cache)
+    };
+    let stopped = answer(OverflowPolicy::Stop, &prompt, &mut cache).unwrap();
+    assert_eq!(stopped.len(), 12 + 1 + 5);
+    assert_eq!(cache.len(), 16);
+    // a prompt that doesn't fit is refused
+    let long_prompt = [&prompt[..], &prompt[..]].concat();
+    let fits = answer(OverflowPolicy::Stop, &long_prompt, &mut model.new_cache());
+    assert!(fits.is_ok());
+    cache.truncate(0);
+    let refused = answer(OverflowPolicy::Stop, &long_prompt, &mut cache);
+    assert_eq!(refused.err().map(|full| full.requested), Some(24));
+
+    // sliding keeps half of the context and goes on
+    cache.truncate(0);
+    let slid = answer(OverflowPolicy::Slide, &prompt, &mut cache).unwrap();
+    assert_eq!(slid.len(), 12 + 1 + 30);
+    assert!(cache.len() <= 16);
+    // unless half of the context doesn't fit either: 2 blocks hold 8 positions
+    let pool = model.new_block_pool(4, 2);
+    let slid = answer(
+        OverflowPolicy::Slide,
+        &prompt,
+        &mut model.new_paged_cache(&pool),
+    );
+    assert!(slid.is_err());
+}
+
+#[test]
+pub fn test_sink_window_cache() {
+    use crate::test_util::{max_abs_diff, story_model, test_tokens};
+    let mut model = story_model();
+    let tokens = test_tokens(20);
+    let input = |tokens: &[u32]| Tensor::new(tokens.to_vec(), &[tokens.len()]);
+
+    // the window needs room to slide
+    let max_seq_len = model.max_seq_len();
+    assert!(model.new_sink_window_cache(max_seq_len).is_none());
+    assert!(model.new_sink_window_cache(max_seq_len - 1).is_none());
+    assert!(model.new_sink_window_cache(max_seq_len - 2).is_some());
+
+    // until the window is full, it gives the same logits as a plain cache
+    let (mut full, mut window) = (model.new_cache(), model.new_sink_window_cache(4).unwrap());
+    let expected = model.forward(&input(&tokens[..13]), &mut full).unwrap();
+    let logits = model.forward(&input(&tokens[..13]), &mut window).unwrap();
+    assert_eq!(logits.data(), expected.data());
+
+    // with one layer K and V only depend on the token and its position, so once
+    // the window slides the cache equals a fresh encoding of the tokens it kept
+    model.n_layers = 1;
+    let dim = model.n_kv_h * model.dqkv;
+    let mut cache = KVCache::with_sink_window(1, 4, 6, dim);
+    let mut logits = None;
+    for &token in &tokens {
+        logits = Some(model.forward(&input(&[token]), &mut cache).unwrap());
+    }
+    assert_eq!(cache.len(), 10);
+    let kept = [&tokens[..4], &tokens[14..]].concat();
+    let expected = model
+        .forward(&input(&kept), &mut model.new_cache())
+        .unwrap();
+    let max_diff = max_abs_diff(logits.unwrap().data(), expected.data());
+    assert!(max_diff < 1e-4, "max logit diff {max_diff}");
+
+    // a prompt longer than the window goes in a window at a time
+    let mut cache = KVCache::with_sink_window(1, 4, 6, dim);
+    model.forward(&input(&tokens), &mut cache).unwrap();
+    assert_eq!(cache.len(), 10);
+
+    // sliding past the 1024 positions of the rope table turns the keys back
+    let tokens = test_tokens(1200);
+    let mut cache = KVCache::with_sink_window(1, 4, 6, dim);
+    let mut logits = None;
+    for chunk in tokens.chunks(5) {
+        logits = Some(model.forward(&input(chunk), &mut cache).unwrap());
+    }
+    assert!(cache.shift() < 200);
+    let kept = [&tokens[..4], &tokens[1194..]].concat();
+    let expected = model
+        .forward(&input(&kept), &mut model.new_cache())
+        .unwrap();
+    let max_diff = max_abs_diff(logits.unwrap().data(), expected.data());
+    assert!(max_diff < 1e-4, "max logit diff {max_diff}");
+}
+
+#[test]
+pub fn test_paged_cache() {
+    use crate::test_util::{story_model, test_tokens};
+    let model = story_model();
+    let input = |tokens: &[u32]| Tensor::new(tokens.to_vec(), &[tokens.len()]);
+    let prompts: [Vec<u32>; 2] = [test_tokens(13), (0..6).map(|i| 5 + i * 31 % 2000).collect()];
+
+    // two sessions interleaved on one pool give the logits of dense caches
+    let pool = model.new_block_pool(4, 8);
+    let mut paged = [model.new_paged_cache(&pool), model.new_paged_cache(&pool)];
+    let mut dense = [model.new_cache(), model.new_cache()];
+    for (i, prompt) in prompts.iter().enumerate() {
+        let expected = model.forward(&input(prompt), &mut dense[i]).unwrap();
+        let logits = model.forward(&input(prompt), &mut paged[i]).unwrap();
+        assert_eq!(logits.data(), expected.data());
+    }
+    // 13 and 6 positions take 4 and 2 blocks
+    assert_eq!(pool.in_use(), 6);
+    for token in [100, 200, 300] {
+        for i in 0..2 {
+            let expected = model.forward(&input(&[token]), &mut dense[i]).unwrap();
+            let logits = model.forward(&input(&[token]), &mut paged[i]).unwrap();
+            assert_eq!(logits.data(), expected.data());
+        }
+    }
+    assert_eq!(pool.in_use(), 7);
+
+    // the pool is shared, so one session can fill it for the other
+    let [first, second] = &mut paged;
+    let full = ContextFull {
+        cached: 9,
+        requested: 8,
+        capacity: 16,
+    };
+    assert_eq!(model.forward(&input(&[7; 8]), second).err(), Some(full));
+    assert_eq!(second.len(), 9);
+    model.forward(&input(&[7; 7]), second).unwrap();
+    assert_eq!(pool.available(), 0);
+
+    // blocks come back when a session shrinks or ends
+    first.truncate(5);
+    assert_eq!(pool.in_use(), 6);
+    drop(paged);
+    assert_eq!((pool.in_use(), pool.available()), (0, 8));
+}
+
+#[test]
+pub fn test_prefix_cache() {
+    use crate::kvcache::PrefixCache;
+    use crate::test_util::{story_model, test_tokens};
+    let model = story_model();
+    let input = |tokens: &[u32]| Tensor::new(tokens.to_vec(), &[tokens.len()]);
+    let first = test_tokens(13);
+    // the same first 9 tokens, so the second sequence diverges in its third block
+    let second = [&first[..9], &[11, 12, 13, 14, 15]].concat();
+
+    let pool = model.new_block_pool(4, 16);
+    let mut prefixes = PrefixCache::new(&pool, 16);
+    let mut a = model.new_paged_cache(&pool);
+    model.forward(&input(&first), &mut a).unwrap();
+    prefixes.insert(&first, &a);
+
+    let mut b = model.new_paged_cache(&pool);
+    let cached = prefixes.lookup(&second[..second.len() - 1], &mut b);
+    assert_eq!(cached, 9);
+    let logits = model.forward(&input(&second[cached..]), &mut b).unwrap();
+    let expected = model
+        .forward(&input(&second), &mut model.new_cache())
+        .unwrap();
+    assert_eq!(logits.data(), expected.data());
+
+    // the first sequence still sees its own keys after the second one wrote
+    let mut dense = model.new_cache();
+    model.forward(&input(&first), &mut dense).unwrap();
+    let expected = model.forward(&input(&[100]), &mut dense).unwrap();
+    let logits = model.forward(&input(&[100]), &mut a).unwrap();
+    assert_eq!(logits.data(), expected.data());
+    assert_eq!(prefixes.stats().token_hit_rate(), 9. / 13.);
+}
+
+#[test]
+pub fn test_forward_streaming() {
+    use crate::test_util::{max_abs_diff, story_model};
+    let mut model = story_model();
+    let prompt = Tensor::<u32>::new(vec![1, 300, 400, 500, 600, 700], &[6]);
+    let next = Tensor::<u32>::new(vec![800], &[1]);
+    let mut run = |attention| {
+        model.set_attention(attention);
+        let mut cache = model.new_cache();
+        let prefill = model.forward(&prompt, &mut cache).unwrap();
+        (prefill, model.forward(&next, &mut cache).unwrap())
+    };
+    let (expected_prefill, expected_decode) = run(Attention::Full);
+    assert_eq!("full".parse(), Ok(Attention::Full));
+    assert_eq!("streaming:4".parse(), Ok(Attention::Streaming { block: 4 }));
+    assert!("streaming:0".parse::<Attention>().is_err());
+    assert!("blocked".parse::<Attention>().is_err());
+    let (prefill, decode) = run(Attention::Streaming { block: 4 });
+    for (result, expected) in [(prefill, expected_prefill), (decode, expected_decode)] {
+        let max_diff = max_abs_diff(result.data(), expected.data());
+        assert!(max_diff < 1e-4, "max logit diff {max_diff}");
+    }
+}
+
 #[test]
 pub fn test_load_safetensors() {
     use crate::tensor::float_eq;
     use std::path::PathBuf;
     let project_dir = env!("CARGO_MANIFEST_DIR");
     let model_dir = PathBuf::from(project_dir).join("models").join("story");
-    let model = Llama::from_safetensors(model_dir);
+    let model = Llama::<f32>::from_safetensors(model_dir).unwrap();
     assert_eq!(model.vocab, 2048);
     assert_eq!(model.n_layers, 2);
     assert_eq!(model.n_q_h, 8);
@@ -378,9 +1336,15 @@ pub fn test_load_safetensors() {
         1e-6
     ));
     assert_eq!(
-        model.params.lm_head.data()[10],
+        model.params.lm_head.as_dense().unwrap().data()[10],
         model.params.embedding_table.data()[10]
     );
+    assert_eq!(
+        model.params.lm_head.as_dense().unwrap().data().as_ptr(),
+        model.params.embedding_table.data().as_ptr()
+    );
+    // the story model is stored as f32, so its weights are used in place
+    assert!(model.params.wq[0].as_dense().unwrap().is_mapped());
     assert!(float_eq(
         &model.params.rms_att_w[0].data()[10],
         &0.18652344,
@@ -397,30 +1361,470 @@ pub fn test_load_safetensors() {
         1e-6
     ));
     assert!(float_eq(
-        &model.params.w_down[0].data()[100],
+        &model.params.w_down[0].as_dense().unwrap().data()[100],
         &-0.0625,
         1e-6
     ));
-    assert!(float_eq(&model.params.w_up[0].data()[100], &1.46875, 1e-6));
     assert!(float_eq(
-        &model.params.w_gate[1].data()[100],
+        &model.params.w_up[0].as_dense().unwrap().data()[100],
+        &1.46875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.w_gate[1].as_dense().unwrap().data()[100],
         &0.296875,
         1e-6
     ));
     assert!(float_eq(
-        &model.params.wq[1].data()[100],
+        &model.params.wq[1].as_dense().unwrap().data()[100],
         &0.032226563,
         1e-6
     ));
     assert!(float_eq(
-        &model.params.wk[1].data()[100],
+        &model.params.wk[1].as_dense().unwrap().data()[100],
         &-0.21386719,
         1e-6
     ));
     assert!(float_eq(
-        &model.params.wv[0].data()[100],
+        &model.params.wv[0].as_dense().unwrap().data()[100],
         &0.041015625,
         1e-6
     ));
-    assert!(float_eq(&model.params.wo[0].data()[100], &0.01965332, 1e-6));
+    assert!(float_eq(
+        &model.params.wo[0].as_dense().unwrap().data()[100],
+        &0.01965332,
+        1e-6
+    ));
+}
+
+#[test]
+pub fn test_load_missing_dir() {
+    let result = Llama::<f32>::from_safetensors("models/no-such-model");
+    assert!(matches!(
+        result,
+        Err(LoadError::MissingFile { path, .. }) if path.ends_with("config.json")
+    ));
+}
+
+#[test]
+pub fn test_forward_half_precision() {
+    use crate::test_util::{max_abs_diff, story_dir};
+    use half::{bf16, f16};
+    fn logits<T: Float>(model_dir: &Path, input: &Tensor<u32>) -> Tensor<f32> {
+        let model = Llama::<T>::from_safetensors(model_dir).unwrap();
+        let mut cache = model.new_cache();
+        model.forward(input, &mut cache).unwrap()
+    }
+    let model_dir = story_dir();
+    let input = Tensor::<u32>::new(vec![1, 300, 400, 500, 600, 700], &[6]);
+    let reference = logits::<f32>(&model_dir, &input);
+    // half precision weights and activations stay close to the f32 logits
+    for (result, tolerance) in [
+        (logits::<f16>(&model_dir, &input), 0.05),
+        (logits::<bf16>(&model_dir, &input), 0.25),
+    ] {
+        let max_diff = max_abs_diff(reference.data(), result.data());
+        assert!(max_diff < tolerance, "max logit diff {max_diff}");
+        assert_eq!(
+            OP::random_sample(&result, 1., 1, 0.),
+            OP::random_sample(&reference, 1., 1, 0.)
+        );
+    }
+}
+
+#[test]
+pub fn test_forward_int8() {
+    use crate::quant::WeightFormat;
+    use crate::test_util::{max_abs_diff, story_dir};
+    let model_dir = story_dir();
+    let options = LoadOptions {
+        linear: WeightFormat::Int8,
+        lm_head: WeightFormat::Int8,
+        ..Default::default()
+    };
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let quantized = Llama::<f32>::from_safetensors_with(&model_dir, &options).unwrap();
+    assert_eq!(quantized.params.wq[0].format(), WeightFormat::Int8);
+    assert_eq!(quantized.params.lm_head.format(), WeightFormat::Int8);
+
+    let input = Tensor::<u32>::new(vec![1, 300, 400, 500, 600, 700], &[6]);
+    let reference = model.forward(&input, &mut model.new_cache()).unwrap();
+    let result = quantized
+        .forward(&input, &mut quantized.new_cache())
+        .unwrap();
+    let max_diff = max_abs_diff(reference.data(), result.data());
+    assert!(max_diff < 0.5, "max logit diff {max_diff}");
+    assert_eq!(
+        OP::random_sample(&result, 1., 1, 0.),
+        OP::random_sample(&reference, 1., 1, 0.)
+    );
+}
+
+#[test]
+pub fn test_forward_q4() {
+    use crate::quant::WeightFormat;
+    use crate::test_util::{max_abs_diff, story_dir};
+    let model_dir = story_dir();
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let input = Tensor::<u32>::new(vec![1, 300, 400, 500, 600, 700], &[6]);
+    let reference = model.forward(&input, &mut model.new_cache()).unwrap();
+
+    for format in [WeightFormat::Q4_0, WeightFormat::Q4_1] {
+        // 4-bit projections, with the lm_head and one layer's down_proj kept
+        // dense per tensor
+        let down_proj = "model.layers.1.mlp.down_proj.weight";
+        let options = LoadOptions {
+            linear: format,
+            lm_head: format,
+            overrides: [
+                (down_proj.to_string(), WeightFormat::Dense),
+                ("lm_head.weight".to_string(), WeightFormat::Dense),
+            ]
+            .into(),
+        };
+        let quantized = Llama::<f32>::from_safetensors_with(&model_dir, &options).unwrap();
+        assert_eq!(quantized.params.wq[0].format(), format);
+        assert_eq!(quantized.params.w_down[0].format(), format);
+        assert_eq!(quantized.params.w_down[1].format(), WeightFormat::Dense);
+        assert_eq!(quantized.params.lm_head.format(), WeightFormat::Dense);
+        // the embedding table still backs the tied lm_head
+        assert!(quantized.params.is_tied());
+        assert_eq!(
+            quantized.params.embedding_table.data(),
+            model.params.embedding_table.data()
+        );
+
+        let result = quantized
+            .forward(&input, &mut quantized.new_cache())
+            .unwrap();
+
+        // the same weights dequantized to f32 carry the whole quantization
+        // error, so only the order of the sums tells the 4-bit kernels apart
+        let mut dequantized = Llama::<f32>::from_safetensors_with(&model_dir, &options).unwrap();
+        let params = &mut dequantized.params;
+        for w in (params.wq.iter_mut())
+            .chain(&mut params.wk)
+            .chain(&mut params.wv)
+            .chain(&mut params.wo)
+            .chain(&mut params.w_up)
+            .chain(&mut params.w_gate)
+            .chain(&mut params.w_down)
+        {
+            *w = Weight::Dense(w.dequantize());
+        }
+        let expected = dequantized
+            .forward(&input, &mut dequantized.new_cache())
+            .unwrap();
+        let max_diff = max_abs_diff(expected.data(), result.data());
+        assert!(max_diff < 1e-4, "{format:?}: max logit diff {max_diff}");
+        assert_eq!(
+            OP::random_sample(&result, 1., 1, 0.),
+            OP::random_sample(&reference, 1., 1, 0.),
+            "{format:?}"
+        );
+    }
+}
+
+#[test]
+pub fn test_load_gguf() {
+    use crate::gguf::{write_gguf, GGML_TYPE_F16, GGML_TYPE_F32, GGML_TYPE_Q8_0};
+    use crate::test_util::{max_abs_diff, story_dir};
+    let model_dir = story_dir();
+    let config: LlamaConfigJson =
+        serde_json::from_reader(File::open(model_dir.join("config.json")).unwrap()).unwrap();
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let input = Tensor::<u32>::new(vec![1, 300, 400, 500, 600, 700], &[6]);
+    let reference = model.forward(&input, &mut model.new_cache()).unwrap();
+
+    // (norm type, projection type): lossless, then quantized like a typical GGUF
+    for (norm_type, linear_type) in [
+        (GGML_TYPE_F32, GGML_TYPE_F32),
+        (GGML_TYPE_F16, GGML_TYPE_Q8_0),
+    ] {
+        let p = &model.params;
+        // the story model is tied, so like llama.cpp only the embedding table is written
+        let mut tensors = vec![
+            (
+                "model.embed_tokens.weight".to_string(),
+                GGML_TYPE_F32,
+                &p.embedding_table,
+            ),
+            ("model.norm.weight".to_string(), norm_type, &p.rms_out_w),
+        ];
+        for layer in 0..model.n_layers {
+            let name = |name: &str| format!("model.layers.{layer}.{name}");
+            tensors.push((
+                name("input_layernorm.weight"),
+                norm_type,
+                &p.rms_att_w[layer],
+            ));
+            tensors.push((
+                name("post_attention_layernorm.weight"),
+                norm_type,
+                &p.rms_ffn_w[layer],
+            ));
+            for (name_, weight) in [
+                ("self_attn.q_proj.weight", &p.wq[layer]),
+                ("self_attn.k_proj.weight", &p.wk[layer]),
+                ("self_attn.v_proj.weight", &p.wv[layer]),
+                ("self_attn.o_proj.weight", &p.wo[layer]),
+                ("mlp.up_proj.weight", &p.w_up[layer]),
+                ("mlp.gate_proj.weight", &p.w_gate[layer]),
+                ("mlp.down_proj.weight", &p.w_down[layer]),
+            ] {
+                tensors.push((name(name_), linear_type, weight.as_dense().unwrap()));
+            }
+        }
+        let path = std::env::temp_dir().join(format!(
+            "learning-lm-rs-{}-{linear_type}.gguf",
+            std::process::id()
+        ));
+        write_gguf(&config, &tensors, &path).unwrap();
+        let loaded = Llama::<f32>::from_gguf(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.n_layers, model.n_layers);
+        assert_eq!(loaded.n_kv_h, model.n_kv_h);
+        assert_eq!(loaded.vocab, model.vocab);
+        assert_eq!(loaded.rope_theta, model.rope_theta);
+        assert_eq!(loaded.eos_token_id, model.eos_token_id);
+        let result = loaded.forward(&input, &mut loaded.new_cache()).unwrap();
+        if linear_type == GGML_TYPE_F32 {
+            // q and k come back in HF order, and f32 tensors are used in place
+            assert_eq!(
+                loaded.params.wk[1].as_dense().unwrap().data(),
+                model.params.wk[1].as_dense().unwrap().data()
+            );
+            assert!(loaded.params.rms_out_w.is_mapped());
+            assert!(result.close_to(&reference, 1e-6));
+        } else {
+            let max_diff = max_abs_diff(reference.data(), result.data());
+            assert!(max_diff < 0.5, "max logit diff {max_diff}");
+        }
+    }
+}
+
+#[test]
+pub fn test_dynamic_rope() {
+    use crate::test_util::{max_abs_diff, story_dir, story_model, test_tokens};
+    let model_dir = story_dir();
+    let mut config: LlamaConfigJson =
+        serde_json::from_reader(File::open(model_dir.join("config.json")).unwrap()).unwrap();
+    config.rope_scaling = Some(RopeScaling::Dynamic { factor: 2. });
+    let params = LLamaParams::from_model_dir(&model_dir, &config).unwrap();
+    let dynamic = Llama::<f32>::new(&config, params);
+    let model = story_model();
+    // the context doubles, and its trained part is rotated as before
+    assert_eq!(dynamic.max_seq_len(), 2 * model.max_seq_len());
+    assert_eq!(
+        dynamic.new_sink_window_cache(4).unwrap().capacity(),
+        model.max_seq_len()
+    );
+    let tokens = test_tokens(600);
+    let prefix = Tensor::new(tokens[..8].to_vec(), &[8]);
+    assert_eq!(
+        dynamic
+            .forward(&prefix, &mut dynamic.new_cache())
+            .unwrap()
+            .data(),
+        model
+            .forward(&prefix, &mut model.new_cache())
+            .unwrap()
+            .data()
+    );
+
+    // past max_position_embeddings, decoding token by token rotates each
+    // position the same as a prefill over all of them
+    let all = dynamic
+        .forward(
+            &Tensor::new(tokens.clone(), &[600]),
+            &mut dynamic.new_cache(),
+        )
+        .unwrap();
+    let mut cache = dynamic.new_cache();
+    let mut logits = dynamic
+        .forward(&Tensor::new(tokens[..590].to_vec(), &[590]), &mut cache)
+        .unwrap();
+    for &token in &tokens[590..] {
+        logits = dynamic
+            .forward(&Tensor::new(vec![token], &[1]), &mut cache)
+            .unwrap();
+    }
+    let diff = max_abs_diff(all.data(), logits.data());
+    assert!(diff < 1e-4, "max logit diff {diff}");
+}
+
+#[test]
+pub fn test_save_safetensors() {
+    use crate::quant::{Q8Tensor, WeightFormat};
+    use crate::test_util::story_dir;
+    use safetensors::Dtype;
+    let model_dir = story_dir();
+    let model = Llama::<f32>::from_safetensors(&model_dir).unwrap();
+    let all_tensors = |model: &Llama<f32>| {
+        let mut tensors = vec![];
+        model.params.for_each_weight(|name, weight, linear| {
+            tensors.push((name, weight.dequantize(), linear))
+        });
+        tensors
+    };
+    let original = all_tensors(&model);
+
+    let save_dir = std::env::temp_dir().join(format!("learning-lm-rs-save-{}", std::process::id()));
+    for options in [
+        SaveOptions::default(),
+        SaveOptions {
+            dtype: Some(Dtype::BF16),
+            ..Default::default()
+        },
+        SaveOptions {
+            int8_linear: true,
+            ..Default::default()
+        },
+    ] {
+        model.save_safetensors_with(&save_dir, &options).unwrap();
+        let reloaded = Llama::<f32>::from_safetensors(&save_dir).unwrap();
+        assert!(reloaded.params.is_tied());
+        assert_eq!(reloaded.n_kv_h, model.n_kv_h);
+        assert_eq!(reloaded.eps, model.eps);
+        // int8 projections reload as int8, everything else stays dense
+        let linear_format = match options.int8_linear {
+            true => WeightFormat::Int8,
+            false => WeightFormat::Dense,
+        };
+        assert!((reloaded.params.wq.iter()).all(|w| w.format() == linear_format));
+        assert!((reloaded.params.w_down.iter()).all(|w| w.format() == linear_format));
+        assert_eq!(reloaded.params.lm_head.format(), WeightFormat::Dense);
+
+        let reloaded = all_tensors(&reloaded);
+        assert_eq!(reloaded.len(), original.len());
+        for ((name, a, linear), (reloaded_name, b, _)) in original.iter().zip(&reloaded) {
+            assert_eq!(name, reloaded_name);
+            let expected = match (options.dtype, options.int8_linear && *linear) {
+                (_, true) => Q8Tensor::quantize(a).dequantize(),
+                (Some(Dtype::BF16), _) => Tensor::new(
+                    a.data()
+                        .iter()
+                        .map(|&x| half::bf16::from_f32(x).to_f32())
+                        .collect(),
+                    a.shape(),
+                ),
+                _ => a.clone(),
+            };
+            assert_eq!(b.shape(), expected.shape(), "{name}");
+            assert_eq!(b.data(), expected.data(), "{name}");
+        }
+    }
+
+    // overwriting the files a loaded model maps leaves that model intact
+    model.save_safetensors(&save_dir).unwrap();
+    let mapped = Llama::<f32>::from_safetensors(&save_dir).unwrap();
+    assert!(mapped.params.rms_out_w.is_mapped());
+    let input = Tensor::<u32>::new(vec![1, 300, 400], &[3]);
+    let before = mapped.forward(&input, &mut mapped.new_cache()).unwrap();
+    let int8 = SaveOptions {
+        int8_linear: true,
+        ..Default::default()
+    };
+    mapped.save_safetensors_with(&save_dir, &int8).unwrap();
+    let after = mapped.forward(&input, &mut mapped.new_cache()).unwrap();
+    assert_eq!(before.data(), after.data());
+    // no temporary files are left behind
+    let mut files: Vec<_> = std::fs::read_dir(&save_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["config.json", "model.safetensors"]);
+    std::fs::remove_dir_all(&save_dir).unwrap();
+}
+
+#[test]
+pub fn test_explicit_head_dim() {
+    use crate::params::{expected_tensor_names, tiny_config, tiny_shape};
+    use crate::test_util::{max_abs_diff, values};
+    use std::collections::HashMap;
+    // two heads of width 8 on a hidden size of 8
+    let mut config = serde_json::to_value(tiny_config(2, true)).unwrap();
+    config["head_dim"] = 8.into();
+    let config: LlamaConfigJson = serde_json::from_value(config).unwrap();
+    assert_eq!(config.head_dim(), 8);
+
+    let tensors: HashMap<String, Tensor<f32>> = (expected_tensor_names(&config).into_iter())
+        .enumerate()
+        .map(|(i, name)| {
+            let shape = tiny_shape(&name, 8);
+            let len = shape.iter().product();
+            let tensor = Tensor::new(values(len, 0.37 + i as f32 * 0.11), &shape);
+            (name, tensor)
+        })
+        .collect();
+    let params = LLamaParams::from_tensors(tensors, &config).unwrap();
+    assert_eq!(params.wq[0].as_dense().unwrap().shape(), &[16, 8]);
+    assert_eq!(params.wo[0].as_dense().unwrap().shape(), &[8, 16]);
+    let model = Llama::new(&config, params);
+    let input = Tensor::<u32>::new(vec![1, 2, 3], &[3]);
+    let logits = model.forward(&input, &mut model.new_cache()).unwrap();
+    assert_eq!(logits.shape(), &[1, 10]);
+
+    // the same forward written out, with the uncached rope and the reference
+    // attention turning and attending over heads of 8
+    let params = &model.params;
+    let dense = |w: &Weight<f32>| w.as_dense().unwrap().clone();
+    let inv_freq: Vec<f32> = (0..4)
+        .map(|i| 1. / config.rope_theta.powf(i as f32 / 4.))
+        .collect();
+    let mut residual = Tensor::<f32>::default(&[3, 8]);
+    OP::gather(&mut residual, &input, &params.embedding_table);
+    let mut hidden = Tensor::<f32>::default(&[3, 8]);
+    for layer in 0..2 {
+        OP::rms_norm(
+            &mut hidden,
+            &residual,
+            &params.rms_att_w[layer],
+            config.rms_norm_eps,
+        );
+        let mut q = Tensor::<f32>::default(&[3, 16]);
+        let mut k = Tensor::<f32>::default(&[3, 8]);
+        let mut v = Tensor::<f32>::default(&[3, 8]);
+        OP::matmul_transb(&mut q, 0., &hidden, &dense(&params.wq[layer]), 1.);
+        OP::matmul_transb(&mut k, 0., &hidden, &dense(&params.wk[layer]), 1.);
+        OP::matmul_transb(&mut v, 0., &hidden, &dense(&params.wv[layer]), 1.);
+        OP::rope(q.reshape(&[3, 2, 8]), 0, &inv_freq, 1.);
+        OP::rope(k.reshape(&[3, 1, 8]), 0, &inv_freq, 1.);
+        let mut attn = Tensor::<f32>::default(&[3, 16]);
+        let mut scores = Tensor::<f32>::default(&[1, 2, 3, 3]);
+        self_attention_reference(
+            &mut attn,
+            &mut scores,
+            q.reshape(&[3, 16]),
+            k.reshape(&[3, 8]),
+            &v,
+            1,
+            2,
+            3,
+            3,
+            8,
+        );
+        OP::matmul_transb(&mut residual, 1., &attn, &dense(&params.wo[layer]), 1.);
+        mlp(
+            &mut residual,
+            &mut hidden,
+            &mut Tensor::default(&[3, 16]),
+            &mut Tensor::default(&[3, 16]),
+            &params.w_up[layer],
+            &params.w_down[layer],
+            &params.w_gate[layer],
+            &params.rms_ffn_w[layer],
+            config.rms_norm_eps,
+        );
+    }
+    let last = residual.slice(2 * 8, &[1, 8]);
+    let mut hidden = Tensor::<f32>::default(&[1, 8]);
+    OP::rms_norm(&mut hidden, &last, &params.rms_out_w, config.rms_norm_eps);
+    let mut expected = Tensor::<f32>::default(&[1, 10]);
+    OP::matmul_transb(&mut expected, 0., &hidden, &dense(&params.lm_head), 1.);
+    let max_diff = max_abs_diff(logits.data(), expected.data());
+    assert!(max_diff < 1e-5, "max logit diff {max_diff}");
 }
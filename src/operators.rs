@@ -1,7 +1,17 @@
-use crate::tensor::Tensor;
+use crate::config::RopeScaling;
+use crate::quant::{Q4Tensor, Q8Tensor, Weight};
+use crate::simd;
+use crate::tensor::{Float, Tensor};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::f32::consts::PI;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
 
 // get (row) vectors from a 2D table given a list of indices
-pub fn gather(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
+pub fn gather<T: Float>(y: &mut Tensor<T>, indices: &Tensor<u32>, table: &Tensor<T>) {
     let length = indices.size();
     let table_shape = table.shape();
     assert!(table_shape.len() == 2);
@@ -14,24 +24,220 @@ pub fn gather(y: &mut Tensor<f32>, indices: &Tensor<u32>, table: &Tensor<f32>) {
     }
 }
 
+// The inverse frequency of every rotary pair of a head of width d, and the
+// factor cos and sin are scaled by, for a sequence of seq_len positions.
+// Follows the rope initialization functions of transformers.
+pub fn rope_frequencies(
+    d: usize,
+    theta: f32,
+    scaling: Option<&RopeScaling>,
+    max_position_embeddings: usize,
+    seq_len: usize,
+) -> (Vec<f32>, f32) {
+    let inv_freq = |theta: f32| -> Vec<f32> {
+        (0..d / 2)
+            .map(|i| 1. / theta.powf((i * 2) as f32 / d as f32))
+            .collect()
+    };
+    match *scaling.unwrap_or(&RopeScaling::Linear { factor: 1. }) {
+        RopeScaling::Linear { factor } => (
+            inv_freq(theta).into_iter().map(|f| f / factor).collect(),
+            1.,
+        ),
+        RopeScaling::Dynamic { factor } => {
+            let theta = if seq_len > max_position_embeddings {
+                let ratio = seq_len as f32 / max_position_embeddings as f32;
+                theta * (factor * ratio - (factor - 1.)).powf(d as f32 / (d as f32 - 2.))
+            } else {
+                theta
+            };
+            (inv_freq(theta), 1.)
+        }
+        RopeScaling::Yarn {
+            factor,
+            original_max_position_embeddings,
+            beta_fast,
+            beta_slow,
+            attention_factor,
+        } => {
+            let original =
+                original_max_position_embeddings.unwrap_or(max_position_embeddings) as f32;
+            // the pair that turns `rotations` times over the original context
+            let correction_dim = |rotations: f32| {
+                d as f32 * (original / (rotations * 2. * PI)).ln() / (2. * theta.ln())
+            };
+            let low = correction_dim(beta_fast).floor().max(0.);
+            let mut high = correction_dim(beta_slow).ceil().min(d as f32 - 1.);
+            if low == high {
+                high += 0.001;
+            }
+            let inv_freq = inv_freq(theta)
+                .into_iter()
+                .enumerate()
+                .map(|(i, f)| {
+                    // 0 keeps the frequency, 1 interpolates it
+                    let ramp = ((i as f32 - low) / (high - low)).clamp(0., 1.);
+                    f / factor * ramp + f * (1. - ramp)
+                })
+                .collect();
+            (inv_freq, attention_factor)
+        }
+        RopeScaling::Llama3 {
+            factor,
+            low_freq_factor,
+            high_freq_factor,
+            original_max_position_embeddings,
+        } => {
+            let original = original_max_position_embeddings as f32;
+            let inv_freq = inv_freq(theta)
+                .into_iter()
+                .map(|f| {
+                    let wavelen = 2. * PI / f;
+                    if wavelen < original / high_freq_factor {
+                        f
+                    } else if wavelen > original / low_freq_factor {
+                        f / factor
+                    } else {
+                        let smooth = (original / wavelen - low_freq_factor)
+                            / (high_freq_factor - low_freq_factor);
+                        (1. - smooth) * f / factor + smooth * f
+                    }
+                })
+                .collect();
+            (inv_freq, 1.)
+        }
+    }
+}
+
+// sin and cos of pos * inv_freq[i], scaled by the attention factor, for
+// every position below max_seq_len. They are the same for every head, layer
+// and forward call.
+pub struct RopeTable {
+    sin: Vec<f32>, // (max_seq_len, d / 2)
+    cos: Vec<f32>, // (max_seq_len, d / 2)
+    half: usize,
+    inv_freq: Vec<f32>, // of the first position, which rope_shift turns by
+}
+
+impl RopeTable {
+    pub fn new(max_seq_len: usize, inv_freq: &[f32], attention_factor: f32) -> Self {
+        let mut table = RopeTable {
+            sin: Vec::with_capacity(max_seq_len * inv_freq.len()),
+            cos: Vec::with_capacity(max_seq_len * inv_freq.len()),
+            half: inv_freq.len(),
+            inv_freq: inv_freq.to_vec(),
+        };
+        for pos in 0..max_seq_len {
+            table.push(pos, inv_freq, attention_factor);
+        }
+        table
+    }
+
+    // Dynamic NTK scaling as transformers does it: positions below
+    // max_position_embeddings keep the plain frequencies, and each one past
+    // it is rotated with the frequencies of a sequence that ends there
+    pub fn dynamic(
+        max_seq_len: usize,
+        d: usize,
+        theta: f32,
+        scaling: &RopeScaling,
+        max_position_embeddings: usize,
+    ) -> Self {
+        let frequencies =
+            |seq_len| rope_frequencies(d, theta, Some(scaling), max_position_embeddings, seq_len);
+        let (inv_freq, attention_factor) = frequencies(1);
+        let plain = max_seq_len.min(max_position_embeddings);
+        let mut table = RopeTable::new(plain, &inv_freq, attention_factor);
+        for pos in plain..max_seq_len {
+            let (inv_freq, attention_factor) = frequencies(pos + 1);
+            table.push(pos, &inv_freq, attention_factor);
+        }
+        table
+    }
+
+    fn push(&mut self, pos: usize, inv_freq: &[f32], attention_factor: f32) {
+        for f in inv_freq {
+            let (s, c) = (pos as f32 * f).sin_cos();
+            self.sin.push(s * attention_factor);
+            self.cos.push(c * attention_factor);
+        }
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.sin.len() / self.half.max(1)
+    }
+}
+
+// RoPE with sin and cos looked up in a table instead of recomputed
+pub fn rope_cached<T: Float>(y: &mut Tensor<T>, start_pos: usize, table: &RopeTable) {
+    let shape = y.shape();
+    assert!(shape.len() == 3);
+    let seq_len = shape[0];
+    let n_heads = shape[1];
+    let d = shape[2];
+    assert!(table.half == d / 2);
+    assert!(start_pos + seq_len <= table.max_seq_len());
+    let data = unsafe { y.data_mut() };
+    for tok in 0..seq_len {
+        let pos = start_pos + tok;
+        let sin = &table.sin[pos * d / 2..][..d / 2];
+        let cos = &table.cos[pos * d / 2..][..d / 2];
+        for head in data[tok * n_heads * d..][..n_heads * d].chunks_exact_mut(d) {
+            let (lo, hi) = head.split_at_mut(d / 2);
+            for i in 0..d / 2 {
+                let (a, b) = (lo[i].to_f32(), hi[i].to_f32());
+                lo[i] = T::from_f32(a * cos[i] - b * sin[i]);
+                hi[i] = T::from_f32(b * cos[i] + a * sin[i]);
+            }
+        }
+    }
+}
+
+// Turns rows of heads of width d, already through RoPE, by `delta` more
+// positions, which may be negative. cos and sin aren't scaled again, and the
+// frequencies are those of the first position, so a dynamic table can't be
+// turned past max_position_embeddings.
+pub fn rope_shift<T: Float>(rows: &mut [T], d: usize, delta: isize, table: &RopeTable) {
+    assert!(table.half == d / 2 && rows.len().is_multiple_of(d));
+    let (sin, cos): (Vec<f32>, Vec<f32>) = (table.inv_freq.iter())
+        .map(|f| (delta as f32 * f).sin_cos())
+        .unzip();
+    for head in rows.chunks_exact_mut(d) {
+        let (lo, hi) = head.split_at_mut(d / 2);
+        for i in 0..d / 2 {
+            let (a, b) = (lo[i].to_f32(), hi[i].to_f32());
+            lo[i] = T::from_f32(a * cos[i] - b * sin[i]);
+            hi[i] = T::from_f32(b * cos[i] + a * sin[i]);
+        }
+    }
+}
+
 // RoPE: Rotary Positional Embedding
-pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32) {
+// Rotates the pair (i, i + d/2) of every head by pos * inv_freq[i]
+#[allow(unused)]
+pub fn rope<T: Float>(
+    y: &mut Tensor<T>,
+    start_pos: usize,
+    inv_freq: &[f32],
+    attention_factor: f32,
+) {
     let shape = y.shape();
     assert!(shape.len() == 3);
     let seq_len = shape[0];
     let n_heads = shape[1];
     let d = shape[2];
+    assert!(inv_freq.len() == d / 2);
     let data = unsafe { y.data_mut() };
     for tok in 0..seq_len {
         let pos = start_pos + tok;
         for head in 0..n_heads {
             for i in 0..d / 2 {
-                let a = data[tok * n_heads * d + head * d + i];
-                let b = data[tok * n_heads * d + head * d + i + d / 2];
-                let freq = pos as f32 / theta.powf((i * 2) as f32 / d as f32);
-                let (sin, cos) = freq.sin_cos();
-                data[tok * n_heads * d + head * d + i] = a * cos - b * sin;
-                data[tok * n_heads * d + head * d + i + d / 2] = b * cos + a * sin;
+                let a = data[tok * n_heads * d + head * d + i].to_f32();
+                let b = data[tok * n_heads * d + head * d + i + d / 2].to_f32();
+                let (sin, cos) = (pos as f32 * inv_freq[i]).sin_cos();
+                let (sin, cos) = (sin * attention_factor, cos * attention_factor);
+                data[tok * n_heads * d + head * d + i] = T::from_f32(a * cos - b * sin);
+                data[tok * n_heads * d + head * d + i + d / 2] = T::from_f32(b * cos + a * sin);
             }
         }
     }
@@ -39,7 +245,8 @@ pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32) {
 
 // softmax(x) = exp(x - max) / sum(exp(x - max))
 // y = softmax(mask(x))
-pub fn masked_softmax(y: &mut Tensor<f32>) {
+#[allow(unused)]
+pub fn masked_softmax<T: Float>(y: &mut Tensor<T>) {
     let ndim = y.shape().len();
     assert!(ndim >= 2);
     let seq_len = y.shape()[ndim - 2];
@@ -51,26 +258,24 @@ pub fn masked_softmax(y: &mut Tensor<f32>) {
         for i in 0..seq_len {
             let offset = base + i * total_seq_len;
             let boundary = total_seq_len - seq_len + i + 1;
-
-            let max = data[offset..offset + boundary]
-                .iter()
-                .fold(data[offset], |a, b| a.max(*b));
-
-            let sum = (0..boundary)
-                .map(|j| {
-                    let e = (data[offset + j] - max).exp();
-                    data[offset + j] = e;
-                    e
-                })
-                .sum::<f32>();
-
-            (0..boundary).for_each(|j| data[offset + j] /= sum);
-            (boundary..total_seq_len).for_each(|j| data[offset + j] = 0.0);
+            masked_softmax_row(&mut data[offset..offset + total_seq_len], boundary);
         }
     }
 }
 
-pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon: f32) {
+// Softmax over the first `boundary` elements of a row, zeroing the rest
+pub fn masked_softmax_row<T: Float>(row: &mut [T], boundary: usize) {
+    let (row, masked) = row.split_at_mut(boundary);
+    with_f32_mut(row, |row| {
+        let k = simd::kernels();
+        let max = k.max(row);
+        let sum = k.exp_sum(row, max);
+        k.scale(row, 1. / sum);
+    });
+    masked.fill(T::default());
+}
+
+pub fn rms_norm<T: Float>(y: &mut Tensor<T>, x: &Tensor<T>, w: &Tensor<T>, epsilon: f32) {
     // 确保输入维度 >= 2，最后一维为特征维度
     assert!(y.shape().len() >= 2, "RMSNorm requires at least 2D input");
     let features = y.shape()[y.shape().len() - 1]; // 特征维度（total_seq_len）
@@ -80,80 +285,274 @@ pub fn rms_norm(y: &mut Tensor<f32>, x: &Tensor<f32>, w: &Tensor<f32>, epsilon:
     assert_eq!(w.shape(), &[features], "Weight shape must match features");
 
     let x_data = x.data();
-    let mut y_data = unsafe { y.data_mut() };
-    let w_data = w.data();
+    let y_data = unsafe { y.data_mut() };
+    let w_data = to_f32(w.data());
+    let k = simd::kernels();
 
     // 遍历每个样本（合并 batch 和 seq_len 维度）
     for i in 0..samples {
         // 获取当前样本的 x 切片 [features]
-        let x_slice = &x_data[i * features..(i + 1) * features];
+        let x_slice = to_f32(&x_data[i * features..(i + 1) * features]);
         // 计算平方均值
-        let xi2_mean = x_slice.iter().copied().map(|v| v * v).sum::<f32>() / features as f32;
+        let xi2_mean = k.dot(&x_slice, &x_slice) / features as f32;
         let rms = (xi2_mean + epsilon).sqrt();
 
         // 对每个特征应用缩放和归一化
-        for j in 0..features {
-            y_data[i * features + j] = w_data[j] * x_slice[j] / rms;
-        }
+        with_f32_mut(&mut y_data[i * features..(i + 1) * features], |y| {
+            k.scale_mul(y, &x_slice, &w_data, 1. / rms)
+        });
     }
 }
 
 // y = silu(x) * y
 // hint: this is an element-wise operation
-pub fn swiglu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
+pub fn swiglu<T: Float>(y: &mut Tensor<T>, x: &Tensor<T>) {
     let len = y.size();
     assert!(len == x.size());
 
-    // data[offset:offset+length]
-    let _y = unsafe { y.data_mut() };
-    let _x = x.data();
+    let x = to_f32(x.data());
+    with_f32_mut(unsafe { y.data_mut() }, |y| simd::kernels().silu_mul(y, &x));
+}
 
-    for i in 0.._x.len() {
-        _y[i] = _x[i] / (1.0 + std::f32::consts::E.powf(-_x[i])) * _y[i];
+// `x` as f32s, borrowed when T is f32. The SIMD kernels work on f32 only.
+pub fn to_f32<T: Float>(x: &[T]) -> Cow<'_, [f32]> {
+    if TypeId::of::<T>() == TypeId::of::<f32>() {
+        // SAFETY: T is f32
+        Cow::Borrowed(unsafe { std::slice::from_raw_parts(x.as_ptr().cast(), x.len()) })
+    } else {
+        let mut buf = vec![0.; x.len()];
+        T::slice_to_f32(x, &mut buf);
+        Cow::Owned(buf)
     }
 }
 
+// `x` as f32s: borrowed when T is f32, converted into `buf` otherwise, so that
+// a loop over many rows reuses one allocation
+pub fn to_f32_in<'a, T: Float>(x: &'a [T], buf: &'a mut Vec<f32>) -> &'a [f32] {
+    if TypeId::of::<T>() == TypeId::of::<f32>() {
+        // SAFETY: T is f32
+        unsafe { std::slice::from_raw_parts(x.as_ptr().cast(), x.len()) }
+    } else {
+        buf.resize(x.len(), 0.);
+        T::slice_to_f32(x, buf);
+        buf
+    }
+}
+
+// Run `f` on `x` as f32s: in place when T is f32, on a converted copy that
+// is written back otherwise
+fn with_f32_mut<T: Float, R>(x: &mut [T], f: impl FnOnce(&mut [f32]) -> R) -> R {
+    if TypeId::of::<T>() == TypeId::of::<f32>() {
+        // SAFETY: T is f32
+        f(unsafe { std::slice::from_raw_parts_mut(x.as_mut_ptr().cast(), x.len()) })
+    } else {
+        let mut buf = vec![0.; x.len()];
+        T::slice_to_f32(x, &mut buf);
+        let r = f(&mut buf);
+        x.iter_mut().zip(buf).for_each(|(x, y)| *x = T::from_f32(y));
+        r
+    }
+}
+
+// Output tiles of the matmul kernels: each row of B is used for ROW_TILE
+// rows of A while it is in cache, and a tile of COL_TILE rows of B is one task
+const ROW_TILE: usize = 4;
+const COL_TILE: usize = 64;
+
+static THREAD_POOL: RwLock<Option<Arc<ThreadPool>>> = RwLock::new(None);
+
+// Set the number of threads the matmul kernels and attention run on. 0 means
+// one per core, or RAYON_NUM_THREADS if it is set, which is also the default.
+// Kernels called from inside another rayon pool run on that pool instead.
+pub fn set_num_threads(n: usize) {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(n)
+        .build()
+        .expect("failed to start the matmul threads");
+    *THREAD_POOL.write().unwrap() = Some(Arc::new(pool));
+}
+
+fn thread_pool() -> Arc<ThreadPool> {
+    if let Some(pool) = &*THREAD_POOL.read().unwrap() {
+        return pool.clone();
+    }
+    let mut pool = THREAD_POOL.write().unwrap();
+    pool.get_or_insert_with(|| Arc::new(ThreadPoolBuilder::new().build().unwrap()))
+        .clone()
+}
+
+// Run `f` on the matmul threads, or on the pool the caller already runs on
+pub fn install<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+    match rayon::current_thread_index() {
+        Some(_) => f(),
+        None => thread_pool().install(f),
+    }
+}
+
+// Pointer to C that parallel tiles write their disjoint elements through
+struct TilePtr<T>(*mut T);
+
+unsafe impl<T: Send> Sync for TilePtr<T> {}
+
+impl<T> TilePtr<T> {
+    fn get(&self) -> *mut T {
+        self.0
+    }
+}
+
+// C = beta * C + alpha * S, where tile(rows, cols, s) computes S[rows, cols]
+// into s, row-major. Tiles are computed in parallel on the matmul threads.
+fn par_tiles<T: Float>(
+    c: &mut Tensor<T>,
+    beta: f32,
+    alpha: f32,
+    tile: impl Fn(Range<usize>, Range<usize>, &mut [f32]) + Sync,
+) {
+    let (n_row, n_col) = (c.shape()[0], c.shape()[1]);
+    let col_tiles = n_col.div_ceil(COL_TILE);
+    let n_tiles = n_row.div_ceil(ROW_TILE) * col_tiles;
+    let c = TilePtr(unsafe { c.data_mut() }.as_mut_ptr());
+    install(|| {
+        (0..n_tiles).into_par_iter().for_each(|t| {
+            let (row, col) = (t / col_tiles * ROW_TILE, t % col_tiles * COL_TILE);
+            let rows = row..(row + ROW_TILE).min(n_row);
+            let cols = col..(col + COL_TILE).min(n_col);
+            let mut s = [0f32; ROW_TILE * COL_TILE];
+            let s = &mut s[..rows.len() * cols.len()];
+            tile(rows.clone(), cols.clone(), s);
+            for (i, s_i) in rows.zip(s.chunks_exact(cols.len())) {
+                for (j, s_ij) in cols.clone().zip(s_i) {
+                    // SAFETY: (i, j) is in bounds and belongs to this tile only
+                    let c_ij = unsafe { &mut *c.get().add(i * n_col + j) };
+                    *c_ij = T::from_f32(alpha * s_ij + beta * c_ij.to_f32());
+                }
+            }
+        })
+    });
+}
+
+// The rows of A (n_k wide) in `rows`, as f32
+fn rows_f32<T: Float>(a: &Tensor<T>, rows: Range<usize>, n_k: usize) -> Cow<'_, [f32]> {
+    to_f32(&a.data()[rows.start * n_k..rows.end * n_k])
+}
+
 // C = beta * C + alpha * A @ B^T
 // hint: You don't need to do an explicit transpose of B
-pub fn matmul_transb(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<f32>, alpha: f32) {
+// Products are accumulated in f32 whatever the element type
+pub fn matmul_transb<T: Float>(
+    c: &mut Tensor<T>,
+    beta: f32,
+    a: &Tensor<T>,
+    b: &Tensor<T>,
+    alpha: f32,
+) {
+    let n_k = a.shape()[1];
+    assert!(a.shape()[0] == c.shape()[0]);
+    assert!(b.shape()[1] == n_k && b.shape()[0] == c.shape()[1]);
+    let b = b.data();
+    let k = simd::kernels();
+    par_tiles(c, beta, alpha, |rows, cols, s| {
+        let a_rows = rows_f32(a, rows, n_k);
+        let n = cols.len();
+        let mut b_buf = Vec::with_capacity(n_k);
+        for (jj, j) in cols.enumerate() {
+            let b_j = to_f32_in(&b[j * n_k..][..n_k], &mut b_buf);
+            for (ii, a_i) in a_rows.chunks_exact(n_k).enumerate() {
+                s[ii * n + jj] = k.dot(a_i, b_j);
+            }
+        }
+    });
+}
+
+// The single-threaded triple loop matmul_transb replaced, as a reference
+#[cfg(test)]
+fn matmul_transb_reference<T: Float>(
+    c: &mut Tensor<T>,
+    beta: f32,
+    a: &Tensor<T>,
+    b: &Tensor<T>,
+    alpha: f32,
+) {
     let n_row = c.shape()[0];
     let n_col = c.shape()[1];
-    let n_row_a = a.shape()[0];
-    let n_col_a = a.shape()[1];
-    let n_row_b = b.shape()[0];
-    let n_col_b = b.shape()[1];
-
-    let _a = a.data();
-    let mut _c = unsafe { c.data_mut() };
-    let _b = b.data();
-
+    let n_k = a.shape()[1];
+    let _c = unsafe { c.data_mut() };
     for i in 0..n_row {
         for j in 0..n_col {
-            let a_i = &a.data()[i * n_col_a..][..n_col_a];
-            let b_j = &b.data()[j * n_col_b..][..n_col_b];
-            let mut sum: f32 = 0.0;
+            let a_i = &a.data()[i * n_k..][..n_k];
+            let b_j = &b.data()[j * n_k..][..n_k];
+            let sum: f32 = a_i
+                .iter()
+                .zip(b_j)
+                .map(|(x, y)| x.to_f32() * y.to_f32())
+                .sum();
+            _c[i * n_col + j] = T::from_f32(alpha * sum + beta * _c[i * n_col + j].to_f32());
+        }
+    }
+}
 
-            for k in 0..a_i.len() {
-                sum += a_i[k] * b_j[k];
+// C = beta * C + alpha * A @ B^T, with B stored as int8 rows and a scale per row
+pub fn matmul_transb_q8<T: Float>(
+    c: &mut Tensor<T>,
+    beta: f32,
+    a: &Tensor<T>,
+    b: &Q8Tensor,
+    alpha: f32,
+) {
+    let n_k = a.shape()[1];
+    assert!(a.shape()[0] == c.shape()[0]);
+    assert!(b.shape()[1] == n_k && b.shape()[0] == c.shape()[1]);
+    par_tiles(c, beta, alpha, |rows, cols, s| {
+        let a_rows = rows_f32(a, rows, n_k);
+        let n = cols.len();
+        for (jj, j) in cols.enumerate() {
+            let (b_j, scale) = b.row(j);
+            for (ii, a_i) in a_rows.chunks_exact(n_k).enumerate() {
+                let sum: f32 = a_i.iter().zip(b_j).map(|(x, &q)| x * q as f32).sum();
+                s[ii * n + jj] = scale * sum;
             }
+        }
+    });
+}
 
-            _c[i * n_col + j] = alpha * sum + beta * _c[i * n_col + j];
+// C = beta * C + alpha * A @ B^T, with B stored in 4-bit blocks that are
+// dequantized on the fly
+pub fn matmul_transb_q4<T: Float>(
+    c: &mut Tensor<T>,
+    beta: f32,
+    a: &Tensor<T>,
+    b: &Q4Tensor,
+    alpha: f32,
+) {
+    let n_k = a.shape()[1];
+    assert!(a.shape()[0] == c.shape()[0]);
+    assert!(b.shape()[1] == n_k && b.shape()[0] == c.shape()[1]);
+    par_tiles(c, beta, alpha, |rows, cols, s| {
+        let a_rows = rows_f32(a, rows, n_k);
+        let n = cols.len();
+        for (jj, j) in cols.enumerate() {
+            for (ii, a_i) in a_rows.chunks_exact(n_k).enumerate() {
+                s[ii * n + jj] = b.dot_row(j, a_i);
+            }
         }
+    });
+}
+
+// C = beta * C + alpha * A @ W^T for a dense or quantized weight
+pub fn linear<T: Float>(c: &mut Tensor<T>, beta: f32, a: &Tensor<T>, w: &Weight<T>, alpha: f32) {
+    match w {
+        Weight::Dense(b) => matmul_transb(c, beta, a, b, alpha),
+        Weight::Int8(b) => matmul_transb_q8(c, beta, a, b, alpha),
+        Weight::Q4(b) => matmul_transb_q4(c, beta, a, b, alpha),
     }
 }
 
 // Dot product of two tensors (treated as vectors)
 #[allow(unused)]
-pub fn dot(x: &Tensor<f32>, y: &Tensor<f32>) -> f32 {
+pub fn dot<T: Float>(x: &Tensor<T>, y: &Tensor<T>) -> f32 {
     let len = x.size();
     assert!(len == y.size());
-    let x_ = x.data();
-    let y_ = y.data();
-    let mut sum = 0.0;
-    for i in 0..len {
-        sum += x_[i] * y_[i];
-    }
-    sum
+    simd::kernels().dot(&to_f32(x.data()), &to_f32(y.data()))
 }
 
 // Sample a index from a tensor (treated as a probability vector)
@@ -194,7 +593,7 @@ pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32)
         #[inline]
         fn from((i, p): (usize, &f32)) -> Self {
             Self {
-                val: p.clone(),
+                val: *p,
                 tok: i as _,
             }
         }
@@ -224,38 +623,345 @@ pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32)
 // Your implementation should at least pass the following tests:
 #[test]
 fn test_silu() {
-    let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &vec![1, 3]);
-    let x = Tensor::<f32>::new(vec![1., 2., 3.], &vec![1, 3]);
+    let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &[1, 3]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3.], &[1, 3]);
     swiglu(&mut y, &x);
     assert!(y.close_to(
-        &Tensor::<f32>::new(vec![1.4621172, 5.2847824, 11.43089], &vec![1, 3]),
+        &Tensor::<f32>::new(vec![1.4621172, 5.2847824, 11.43089], &[1, 3]),
         1e-3
     ));
 }
 
 #[test]
 fn test_rms_norm() {
-    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
-    let x = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
-    let w = Tensor::<f32>::new(vec![1., 2.], &vec![2]);
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let w = Tensor::<f32>::new(vec![1., 2.], &[2]);
     rms_norm(&mut y, &x, &w, 1e-6);
     assert!(y.close_to(
-        &Tensor::<f32>::new(
-            vec![0.6324554, 2.5298216, 0.8485281, 2.2627416],
-            &vec![2, 2]
-        ),
+        &Tensor::<f32>::new(vec![0.6324554, 2.5298216, 0.8485281, 2.2627416], &[2, 2]),
         1e-3
     ));
 }
 
 #[test]
 fn test_matmul_transb() {
-    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
-    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
-    let b = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+    let b = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
     matmul_transb(&mut c, 1., &a, &b, 1.);
-    assert!(c.close_to(
-        &Tensor::<f32>::new(vec![15., 34., 35., 81.], &vec![2, 2]),
-        1e-3
-    ));
+    assert!(c.close_to(&Tensor::<f32>::new(vec![15., 34., 35., 81.], &[2, 2]), 1e-3));
+}
+
+#[test]
+fn test_matmul_transb_q8() {
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+    let b = Tensor::<f32>::new(vec![0.1, -0.2, 0.3, 0.4, 0.5, -0.6], &[2, 3]);
+    let mut expected = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    matmul_transb(&mut expected, 0.5, &a, &b, 2.);
+    matmul_transb_q8(&mut c, 0.5, &a, &Q8Tensor::quantize(&b), 2.);
+    // each weight is off by at most half a step of 0.6 / 127
+    for (x, y) in c.data().iter().zip(expected.data()) {
+        assert!((x - y).abs() < 0.05);
+    }
+}
+
+#[test]
+fn test_matmul_transb_q4() {
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+    let b = Q4Tensor::quantize(
+        &Tensor::<f32>::new(vec![0.1, -0.2, 0.3, 0.4, 0.5, -0.6], &[2, 3]),
+        false,
+    );
+    let mut expected = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    // the kernel matches a dense matmul with the dequantized weight
+    matmul_transb(&mut expected, 0.5, &a, &b.dequantize(), 2.);
+    matmul_transb_q4(&mut c, 0.5, &a, &b, 2.);
+    assert!(c.close_to(&expected, 1e-5));
+}
+
+#[test]
+fn test_rope() {
+    // one token at position 2 with a single head of width 4
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[1, 1, 4]);
+    let inv_freq = [0.5, 0.25];
+    rope(&mut y, 2, &inv_freq, 2.);
+    let (sin0, cos0) = 1f32.sin_cos();
+    let (sin1, cos1) = 0.5f32.sin_cos();
+    let expected = Tensor::<f32>::new(
+        vec![
+            2. * (cos0 - 3. * sin0),
+            2. * (2. * cos1 - 4. * sin1),
+            2. * (3. * cos0 + sin0),
+            2. * (4. * cos1 + 2. * sin1),
+        ],
+        &[1, 1, 4],
+    );
+    assert!(y.close_to(&expected, 1e-6));
+}
+
+#[test]
+fn test_rope_shift() {
+    // two heads of width 4, scaled by 2 once
+    let table = RopeTable::new(8, &[0.5, 0.25], 2.);
+    let at = |pos: usize| {
+        let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4., -1., 0., 2., 1.], &[1, 2, 4]);
+        rope_cached(&mut y, pos, &table);
+        y
+    };
+    let close = |a: &Tensor<f32>, b: &Tensor<f32>| {
+        (a.data().iter().zip(b.data())).all(|(a, b)| (a - b).abs() < 1e-5)
+    };
+    let mut y = at(2);
+    rope_shift(unsafe { y.data_mut() }, 4, 3, &table);
+    assert!(close(&y, &at(5)));
+    rope_shift(unsafe { y.data_mut() }, 4, -5, &table);
+    assert!(close(&y, &at(0)));
+}
+
+// Reference values from the rope initialization functions of transformers,
+// for a head of width 16 and theta 10000
+#[test]
+fn test_rope_frequencies() {
+    let check = |scaling: Option<RopeScaling>, seq_len: usize, expected: &[f32], factor: f32| {
+        let (inv_freq, attention_factor) =
+            rope_frequencies(16, 1e4, scaling.as_ref(), 512, seq_len);
+        assert_eq!(inv_freq.len(), expected.len());
+        for (a, b) in inv_freq.iter().zip(expected) {
+            assert!((a - b).abs() <= 1e-5 * b, "{scaling:?}: {inv_freq:?}");
+        }
+        assert!((attention_factor - factor).abs() < 1e-6);
+    };
+    check(
+        None,
+        1,
+        &[
+            1.0,
+            3.162278e-1,
+            1e-1,
+            3.162278e-2,
+            1e-2,
+            3.162278e-3,
+            1e-3,
+            3.162278e-4,
+        ],
+        1.,
+    );
+    check(
+        Some(RopeScaling::Linear { factor: 4. }),
+        1,
+        &[
+            2.5e-1,
+            7.905694e-2,
+            2.5e-2,
+            7.905694e-3,
+            2.5e-3,
+            7.905694e-4,
+            2.5e-4,
+            7.905694e-5,
+        ],
+        1.,
+    );
+    // dynamic NTK only kicks in beyond max_position_embeddings
+    check(
+        Some(RopeScaling::Dynamic { factor: 2. }),
+        512,
+        &[
+            1.0,
+            3.162278e-1,
+            1e-1,
+            3.162278e-2,
+            1e-2,
+            3.162278e-3,
+            1e-3,
+            3.162278e-4,
+        ],
+        1.,
+    );
+    check(
+        Some(RopeScaling::Dynamic { factor: 2. }),
+        1024,
+        &[
+            1.0,
+            2.702961e-1,
+            7.306e-2,
+            1.974783e-2,
+            5.337763e-3,
+            1.442777e-3,
+            3.899769e-4,
+            1.054093e-4,
+        ],
+        1.,
+    );
+    check(
+        Some(RopeScaling::Yarn {
+            factor: 4.,
+            original_max_position_embeddings: None,
+            beta_fast: 32.,
+            beta_slow: 1.,
+            attention_factor: 1.138629,
+        }),
+        1,
+        &[
+            1.0,
+            2.569351e-1,
+            6.25e-2,
+            1.383496e-2,
+            2.5e-3,
+            7.905694e-4,
+            2.5e-4,
+            7.905694e-5,
+        ],
+        1.138629,
+    );
+    check(
+        Some(RopeScaling::Llama3 {
+            factor: 8.,
+            low_freq_factor: 1.,
+            high_freq_factor: 4.,
+            original_max_position_embeddings: 64,
+        }),
+        1,
+        &[
+            1.0,
+            2.443846e-1,
+            1.304226e-2,
+            3.952847e-3,
+            1.25e-3,
+            3.952847e-4,
+            1.25e-4,
+            3.952847e-5,
+        ],
+        1.,
+    );
+}
+
+#[test]
+fn test_rope_cached() {
+    let (inv_freq, attention_factor) = rope_frequencies(8, 1e4, None, 64, 1);
+    let table = RopeTable::new(64, &inv_freq, attention_factor);
+    let data: Vec<f32> = (0..3 * 2 * 8).map(|i| (i as f32 * 0.37).sin()).collect();
+    let mut expected = Tensor::<f32>::new(data.clone(), &[3, 2, 8]);
+    let mut y = Tensor::<f32>::new(data, &[3, 2, 8]);
+    rope(&mut expected, 60, &inv_freq, attention_factor);
+    rope_cached(&mut y, 60, &table);
+    assert!(y.close_to(&expected, 1e-6));
+}
+
+#[test]
+fn test_rope_dynamic() {
+    let scaling = RopeScaling::Dynamic { factor: 2. };
+    let table = RopeTable::dynamic(64, 8, 1e4, &scaling, 32);
+    assert_eq!(table.max_seq_len(), 64);
+    let data: Vec<f32> = (0..2 * 8).map(|i| (i as f32 * 0.37).sin()).collect();
+    for pos in [0, 31, 32, 50, 63] {
+        // the frequencies transformers gives a sequence of pos + 1 positions
+        let (inv_freq, attention_factor) = rope_frequencies(8, 1e4, Some(&scaling), 32, pos + 1);
+        let mut expected = Tensor::<f32>::new(data.clone(), &[1, 2, 8]);
+        let mut y = Tensor::<f32>::new(data.clone(), &[1, 2, 8]);
+        rope(&mut expected, pos, &inv_freq, attention_factor);
+        rope_cached(&mut y, pos, &table);
+        assert!(y.close_to(&expected, 1e-5), "position {pos}");
+    }
+    // positions past max_position_embeddings are rotated slower than plain ones
+    let (plain, _) = rope_frequencies(8, 1e4, None, 32, 1);
+    let mut expected = Tensor::<f32>::new(data.clone(), &[1, 2, 8]);
+    let mut y = Tensor::<f32>::new(data, &[1, 2, 8]);
+    rope(&mut expected, 50, &plain, 1.);
+    rope_cached(&mut y, 50, &table);
+    assert!(!y.close_to(&expected, 1e-3));
+}
+
+// cargo test --release bench_rope -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_rope() {
+    use std::time::Instant;
+    // a decode step of a 32-head model with 128-wide heads
+    let (n_heads, d, max_seq_len, steps) = (32, 128, 4096, 4096);
+    let (inv_freq, attention_factor) = rope_frequencies(d, 5e5, None, max_seq_len, 1);
+    let table = RopeTable::new(max_seq_len, &inv_freq, attention_factor);
+    let mut y = Tensor::<f32>::new(vec![0.5; n_heads * d], &[1, n_heads, d]);
+
+    let start = Instant::now();
+    for pos in 0..steps {
+        rope(&mut y, pos, &inv_freq, attention_factor);
+    }
+    let computed = start.elapsed();
+    let start = Instant::now();
+    for pos in 0..steps {
+        rope_cached(&mut y, pos, &table);
+    }
+    let cached = start.elapsed();
+    println!(
+        "rope per decode step: computed {:?}, cached {:?} ({:.1}x)",
+        computed / steps as u32,
+        cached / steps as u32,
+        computed.as_secs_f64() / cached.as_secs_f64()
+    );
+}
+
+#[test]
+fn test_matmul_transb_tiled() {
+    // sizes that leave partial tiles in both directions
+    use crate::test_util::values;
+    let (m, n, k) = (7, 70, 131);
+    let a = Tensor::<f32>::new(values(m * k, 0.37), &[m, k]);
+    let b = Tensor::<f32>::new(values(n * k, 0.91), &[n, k]);
+    let mut expected = Tensor::<f32>::new(values(m * n, 0.13), &[m, n]);
+    matmul_transb_reference(&mut expected, 0.5, &a, &b, 2.);
+    for threads in [1, 3] {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let mut c = Tensor::<f32>::new(values(m * n, 0.13), &[m, n]);
+        pool.install(|| matmul_transb(&mut c, 0.5, &a, &b, 2.));
+        // only the order of the f32 sums differs
+        for (x, y) in c.data().iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-4, "{x} {y}");
+        }
+    }
+}
+
+// cargo test --release bench_matmul_transb -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_matmul_transb() {
+    use std::time::Instant;
+    let (d, di) = (2048, 5632);
+    let w = Tensor::<f32>::new(vec![0.01; di * d], &[di, d]);
+    for (name, seq_len) in [("decode", 1), ("prefill", 64)] {
+        let x = Tensor::<f32>::new(vec![0.5; seq_len * d], &[seq_len, d]);
+        let mut c = Tensor::<f32>::default(&[seq_len, di]);
+        let runs = if seq_len == 1 { 20 } else { 2 };
+        let start = Instant::now();
+        for _ in 0..runs {
+            matmul_transb_reference(&mut c, 0., &x, &w, 1.);
+        }
+        let reference = start.elapsed() / runs;
+        let start = Instant::now();
+        for _ in 0..runs {
+            matmul_transb(&mut c, 0., &x, &w, 1.);
+        }
+        let tiled = start.elapsed() / runs;
+        println!(
+            "{name} ({seq_len}x{d} @ {di}x{d}^T): reference {reference:?}, tiled {tiled:?} ({:.1}x)",
+            reference.as_secs_f64() / tiled.as_secs_f64()
+        );
+    }
+    // half precision rows are widened to f32 on their way into the dot kernel
+    let w = Tensor::new(vec![half::f16::from_f32(0.01); di * d], &[di, d]);
+    for (name, seq_len) in [("decode", 1), ("prefill", 64)] {
+        let x = Tensor::new(vec![half::f16::from_f32(0.5); seq_len * d], &[seq_len, d]);
+        let mut c = Tensor::default(&[seq_len, di]);
+        let runs = if seq_len == 1 { 20 } else { 2 };
+        let start = Instant::now();
+        for _ in 0..runs {
+            matmul_transb(&mut c, 0., &x, &w, 1.);
+        }
+        println!("f16 {name}: tiled {:?}", start.elapsed() / runs);
+    }
 }
@@ -1,90 +1,1010 @@
 use crate::config::LlamaConfigJson;
-use crate::tensor::Tensor;
-use safetensors::slice::IndexOp;
+use crate::error::{LoadError, SaveError, ShapeMismatch};
+use crate::quant::{Q8Tensor, Weight, WeightFormat};
+use crate::tensor::{Float, Tensor};
+use half::{bf16, f16};
+use memmap2::Mmap;
 use safetensors::tensor::TensorView;
-use safetensors::{SafeTensors, View};
+use safetensors::{Dtype, SafeTensors};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
 
 pub struct LLamaParams<T> {
     // token_id to embedding lookup table
     pub embedding_table: Tensor<T>, // (vocab_size, dim)
     // decoder layer
     pub rms_att_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub wq: Vec<Tensor<T>>,        // (n_heads * head_size, hidden_size) x layers
-    pub wk: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wv: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
-    pub wo: Vec<Tensor<T>>,        // (hidden_size, n_heads * head_size) x layers
+    pub wq: Vec<Weight<T>>,        // (n_heads * head_size, hidden_size) x layers
+    pub wk: Vec<Weight<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wv: Vec<Weight<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wo: Vec<Weight<T>>,        // (hidden_size, n_heads * head_size) x layers
     // ffn layer
     pub rms_ffn_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
-    pub w_up: Vec<Tensor<T>>,      // (intermediate_size, hidden_size) x layers
-    pub w_gate: Vec<Tensor<T>>,    // (intermediate_size, hidden_size) x layers
-    pub w_down: Vec<Tensor<T>>,    // (hidden_size, intermediate_size) x layers
+    pub w_up: Vec<Weight<T>>,      // (intermediate_size, hidden_size) x layers
+    pub w_gate: Vec<Weight<T>>,    // (intermediate_size, hidden_size) x layers
+    pub w_down: Vec<Weight<T>>,    // (hidden_size, intermediate_size) x layers
     pub rms_out_w: Tensor<T>,
-    pub lm_head: Tensor<T>, // (vocab_size, dim)
+    pub lm_head: Weight<T>, // (vocab_size, dim)
 }
 
-impl LLamaParams<f32> {
-    pub fn from_safetensors(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Self {
-        let get_tensor = |name: &str| {
-            safetensor
+// How the loaded weights are stored
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    // attention and mlp projections
+    pub linear: WeightFormat,
+    pub lm_head: WeightFormat,
+    // per-tensor formats by checkpoint name, e.g. "model.layers.0.mlp.down_proj.weight"
+    pub overrides: HashMap<String, WeightFormat>,
+}
+
+impl LoadOptions {
+    fn format_of(&self, name: &str, default: WeightFormat) -> WeightFormat {
+        self.overrides.get(name).copied().unwrap_or(default)
+    }
+}
+
+// How `save_safetensors` writes the weights
+#[derive(Clone, Debug, Default)]
+pub struct SaveOptions {
+    // dtype of dense tensors, the model's own if None
+    pub dtype: Option<Dtype>,
+    // store the attention and mlp projections as int8 with a per-row scale.
+    // The scales go in "{name}_scale" tensors, a layout only this crate reads
+    // back, and it reloads them as int8 weights.
+    pub int8_linear: bool,
+}
+
+// per-layer tensor names, relative to "model.layers.{i}."
+const LAYER_TENSORS: [&str; 9] = [
+    "input_layernorm.weight",
+    "self_attn.q_proj.weight",
+    "self_attn.k_proj.weight",
+    "self_attn.v_proj.weight",
+    "self_attn.o_proj.weight",
+    "post_attention_layernorm.weight",
+    "mlp.up_proj.weight",
+    "mlp.gate_proj.weight",
+    "mlp.down_proj.weight",
+];
+
+const EMBED_TOKENS: &str = "model.embed_tokens.weight";
+const LM_HEAD: &str = "lm_head.weight";
+// int8 weights keep their per-row f32 scales in "{name}_scale"
+const SCALE_SUFFIX: &str = "_scale";
+
+fn layer_tensor_name(layer: usize, name: &str) -> String {
+    format!("model.layers.{layer}.{name}")
+}
+
+// All tensor names the model needs, in a stable order.
+// Tied checkpoints only need the embedding table, which `from_safetensors`
+// also accepts under the lm_head name.
+pub fn expected_tensor_names(config: &LlamaConfigJson) -> Vec<String> {
+    let mut names = vec![EMBED_TOKENS.to_string()];
+    for layer in 0..config.num_hidden_layers {
+        names.extend(
+            LAYER_TENSORS
                 .iter()
-                .find(|(tensor_name, _)| *tensor_name == name)
-                .map(|(_, tensor)| {
-                    let data = tensor
-                        .data()
-                        .chunks_exact(4)
-                        .map(|chunk| {
-                            let mut bytes = [0u8; 4];
-                            for i in 0..chunk.len() {
-                                bytes[chunk.len() - i - 1] = chunk[i];
-                            }
-                            f32::from_be_bytes(bytes)
-                        })
-                        .collect();
-
-                    Tensor::<f32>::new(data, &tensor.shape().to_vec())
-                })
+                .map(|name| layer_tensor_name(layer, name)),
+        );
+    }
+    names.push("model.norm.weight".to_string());
+    if !config.tie_word_embeddings {
+        names.push(LM_HEAD.to_string());
+    }
+    names
+}
+
+// The shape the config implies for a tensor
+fn expected_shape(name: &str, config: &LlamaConfigJson) -> Vec<usize> {
+    let d = config.hidden_size;
+    let dq = config.num_attention_heads * config.head_dim();
+    let dkv = config.num_key_value_heads * config.head_dim();
+    let di = config.intermediate_size;
+    match name.rsplit('.').nth(1).unwrap_or_default() {
+        "embed_tokens" | "lm_head" => vec![config.vocab_size, d],
+        "q_proj" => vec![dq, d],
+        "k_proj" | "v_proj" => vec![dkv, d],
+        "o_proj" => vec![d, dq],
+        "up_proj" | "gate_proj" => vec![di, d],
+        "down_proj" => vec![d, di],
+        // the rms norms
+        _ => vec![d],
+    }
+}
+
+// Check every tensor against the shape the config implies, so that a bad
+// checkpoint fails here instead of panicking in the forward pass
+fn check_tensor_shapes<T: Float>(
+    tensors: &HashMap<String, Weight<T>>,
+    config: &LlamaConfigJson,
+) -> Result<(), LoadError> {
+    let mut names = expected_tensor_names(config);
+    if config.tie_word_embeddings {
+        // the shared table may be stored under the lm_head name
+        names.push(LM_HEAD.to_string());
+    }
+    let mismatches: Vec<ShapeMismatch> = names
+        .into_iter()
+        .filter_map(|name| {
+            let actual = tensors.get(&name)?.shape();
+            let expected = expected_shape(&name, config);
+            (*actual != expected).then(|| ShapeMismatch {
+                actual: actual.clone(),
+                expected,
+                name,
+            })
+        })
+        .collect();
+    if !mismatches.is_empty() {
+        return Err(LoadError::ShapeMismatch(mismatches));
+    }
+    Ok(())
+}
+
+// Widen little-endian floating point data to f32, None for non-float dtypes
+fn convert_to_f32(dtype: Dtype, bytes: &[u8]) -> Option<Vec<f32>> {
+    let data = match dtype {
+        Dtype::F32 => bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+        Dtype::F16 => bytes
+            .chunks_exact(2)
+            .map(|chunk| f16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+            .collect(),
+        Dtype::BF16 => bytes
+            .chunks_exact(2)
+            .map(|chunk| bf16::from_le_bytes(chunk.try_into().unwrap()).to_f32())
+            .collect(),
+        Dtype::F64 => bytes
+            .chunks_exact(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()) as f32)
+            .collect(),
+        _ => return None,
+    };
+    Some(data)
+}
+
+// Narrow f32 data to little-endian `dtype`, None for non-float dtypes
+fn convert_from_f32(dtype: Dtype, data: &[f32]) -> Option<Vec<u8>> {
+    let bytes = match dtype {
+        Dtype::F32 => data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        Dtype::F16 => data
+            .iter()
+            .flat_map(|&x| f16::from_f32(x).to_le_bytes())
+            .collect(),
+        Dtype::BF16 => data
+            .iter()
+            .flat_map(|&x| bf16::from_f32(x).to_le_bytes())
+            .collect(),
+        Dtype::F64 => data
+            .iter()
+            .flat_map(|&x| (x as f64).to_le_bytes())
+            .collect(),
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+// Map a config.json torch_dtype onto the safetensors dtype
+fn torch_dtype(name: &str) -> Option<Dtype> {
+    match name {
+        "float32" => Some(Dtype::F32),
+        "float16" => Some(Dtype::F16),
+        "bfloat16" => Some(Dtype::BF16),
+        "float64" => Some(Dtype::F64),
+        _ => None,
+    }
+}
+
+// The config.json torch_dtype of a safetensors dtype
+pub fn torch_dtype_name(dtype: Dtype) -> Option<&'static str> {
+    ["float32", "float16", "bfloat16", "float64"]
+        .into_iter()
+        .find(|name| torch_dtype(name) == Some(dtype))
+}
+
+// Read back an int8 weight saved by `save_safetensors` with its row scales
+fn load_int8(
+    safetensor: &SafeTensors,
+    name: &str,
+    shape: &[usize],
+    data: &[u8],
+) -> Result<Q8Tensor, LoadError> {
+    let scale_name = format!("{name}{SCALE_SUFFIX}");
+    let scales = safetensor
+        .tensor(&scale_name)
+        .map_err(|_| LoadError::MissingTensor(vec![scale_name.clone()]))?;
+    let rows = shape.first().copied().unwrap_or(1);
+    if shape.len() != 2 || scales.shape() != [rows] {
+        return Err(LoadError::ShapeMismatch(vec![ShapeMismatch {
+            name: scale_name,
+            expected: vec![rows],
+            actual: scales.shape().to_vec(),
+        }]));
+    }
+    let scales = convert_to_f32(scales.dtype(), scales.data()).ok_or_else(|| {
+        LoadError::UnsupportedDtype {
+            name: scale_name,
+            dtype: scales.dtype(),
+        }
+    })?;
+    let data = data.iter().map(|&q| q as i8).collect();
+    Ok(Q8Tensor::from_parts(data, scales, shape.to_vec()))
+}
+
+// Check the checkpoint's tensor names against the config and return the
+// names to load. Missing tensors are an error, unexpected ones are ignored.
+pub fn check_tensor_names<'a>(
+    present: &HashSet<&'a str>,
+    config: &LlamaConfigJson,
+) -> Result<Vec<&'a str>, LoadError> {
+    let mut expected = expected_tensor_names(config);
+    // a tied checkpoint stores the shared table under either name
+    if config.tie_word_embeddings && !present.contains(EMBED_TOKENS) && present.contains(LM_HEAD) {
+        expected[0] = LM_HEAD.to_string();
+    }
+    let missing: Vec<String> = expected
+        .iter()
+        .filter(|name| !present.contains(name.as_str()))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        return Err(LoadError::MissingTensor(missing));
+    }
+    let names: Vec<&str> = expected
+        .iter()
+        .map(|name| *present.get(name.as_str()).unwrap())
+        .collect();
+
+    let mut expected: HashSet<&str> = names.iter().copied().collect();
+    if config.tie_word_embeddings {
+        // the duplicate copy of a tied table is not unexpected
+        expected.insert(LM_HEAD);
+    }
+    let mut unexpected: Vec<&str> = present
+        .difference(&expected)
+        .copied()
+        .filter(|name| {
+            // nor are the scales of int8 weights
+            !name
+                .strip_suffix(SCALE_SUFFIX)
+                .is_some_and(|weight| expected.contains(weight))
+        })
+        .collect();
+    if !unexpected.is_empty() {
+        unexpected.sort_unstable();
+        log::warn!("unexpected tensors in safetensors are ignored: {unexpected:?}");
+    }
+    Ok(names)
+}
+
+// Convert the named tensors of one safetensors file to T. If the file is
+// memory-mapped, tensors already stored as T borrow from `mmap` instead of
+// being copied. Int8 weights stay int8.
+pub fn load_tensors<T: Float>(
+    safetensor: &SafeTensors,
+    mmap: Option<&Arc<Mmap>>,
+    names: &[&str],
+    config: &LlamaConfigJson,
+) -> Result<HashMap<String, Weight<T>>, LoadError> {
+    let mut tensors = HashMap::with_capacity(names.len());
+    let mut other_dtype = vec![];
+    let config_dtype = torch_dtype(&config.torch_dtype);
+    for &name in names {
+        let tensor = safetensor
+            .tensor(name)
+            .map_err(|_| LoadError::MissingTensor(vec![name.to_string()]))?;
+        // checkpoints may keep a few tensors (e.g. norms) in another dtype
+        if tensor.dtype() != Dtype::I8 && config_dtype.is_some_and(|dtype| dtype != tensor.dtype())
+        {
+            other_dtype.push(name);
+        }
+        let mapped = mmap
+            .filter(|_| tensor.dtype() == T::DTYPE)
+            .and_then(|mmap| {
+                let start = tensor.data().as_ptr() as usize - mmap.as_ptr() as usize;
+                Tensor::mapped(mmap.clone(), start, tensor.shape())
+            });
+        let weight = match (mapped, tensor.dtype()) {
+            (Some(tensor), _) => Weight::Dense(tensor),
+            (None, Dtype::I8) => {
+                Weight::Int8(load_int8(safetensor, name, tensor.shape(), tensor.data())?)
+            }
+            (None, dtype) => {
+                let data = convert_to_f32(dtype, tensor.data()).ok_or_else(|| {
+                    LoadError::UnsupportedDtype {
+                        name: name.to_string(),
+                        dtype,
+                    }
+                })?;
+                let data = data.into_iter().map(T::from_f32).collect();
+                Weight::Dense(Tensor::new(data, tensor.shape()))
+            }
         };
+        tensors.insert(name.to_string(), weight);
+    }
+    if !other_dtype.is_empty() {
+        other_dtype.sort_unstable();
+        log::warn!(
+            "tensors stored in a dtype other than torch_dtype {}: {other_dtype:?}",
+            config.torch_dtype
+        );
+    }
+    Ok(tensors)
+}
+
+// Sharded checkpoints map every tensor name to the file holding it
+#[derive(serde::Deserialize)]
+struct SafetensorsIndex {
+    #[serde(deserialize_with = "shard_names")]
+    weight_map: HashMap<String, String>,
+}
+
+// Shards must be plain file names in the model directory, so that an index
+// can't point at files elsewhere
+fn shard_names<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, String>, D::Error> {
+    use serde::de::Error;
+    use std::path::Component;
+    let weight_map: HashMap<String, String> = serde::Deserialize::deserialize(deserializer)?;
+    for file in weight_map.values() {
+        let mut components = Path::new(file).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(D::Error::custom(format!(
+                "invalid shard file name {file:?}"
+            )));
+        }
+    }
+    Ok(weight_map)
+}
 
+fn read_file(path: &Path) -> Result<Vec<u8>, LoadError> {
+    std::fs::read(path).map_err(|source| LoadError::MissingFile {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+pub fn map_file(path: &Path) -> Result<Arc<Mmap>, LoadError> {
+    let missing_file = |source| LoadError::MissingFile {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = File::open(path).map_err(missing_file)?;
+    // SAFETY: model files must not be modified while a model is loaded from them
+    let mmap = unsafe { Mmap::map(&file) }.map_err(missing_file)?;
+    Ok(Arc::new(mmap))
+}
+
+fn deserialize<'a>(bytes: &'a [u8], path: &Path) -> Result<SafeTensors<'a>, LoadError> {
+    SafeTensors::deserialize(bytes).map_err(|source| LoadError::BadSafetensors {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn take_weight<T>(
+    tensors: &mut HashMap<String, Weight<T>>,
+    name: &str,
+) -> Result<Weight<T>, LoadError> {
+    tensors
+        .remove(name)
+        .ok_or_else(|| LoadError::MissingTensor(vec![name.to_string()]))
+}
+
+// Norms and the embedding table are only ever stored dense
+fn into_dense<T>(name: &str, weight: Weight<T>) -> Result<Tensor<T>, LoadError> {
+    match weight {
+        Weight::Dense(tensor) => Ok(tensor),
+        _ => Err(LoadError::UnsupportedDtype {
+            name: name.to_string(),
+            dtype: Dtype::I8,
+        }),
+    }
+}
+
+fn take_tensor<T>(
+    tensors: &mut HashMap<String, Weight<T>>,
+    name: &str,
+) -> Result<Tensor<T>, LoadError> {
+    into_dense(name, take_weight(tensors, name)?)
+}
+
+impl<T: Float> LLamaParams<T> {
+    // Load model.safetensors, or every shard listed in model.safetensors.index.json.
+    // Files are memory-mapped, and weights already stored as T are used in place.
+    pub fn from_model_dir(model_dir: &Path, config: &LlamaConfigJson) -> Result<Self, LoadError> {
+        config.validate()?;
+        let index_path = model_dir.join("model.safetensors.index.json");
+        if !index_path.exists() {
+            let path = model_dir.join("model.safetensors");
+            let mmap = map_file(&path)?;
+            return Self::load_safetensors(&deserialize(&mmap, &path)?, Some(&mmap), config);
+        }
+
+        let index: SafetensorsIndex =
+            serde_json::from_slice(&read_file(&index_path)?).map_err(|source| {
+                LoadError::BadIndex {
+                    path: index_path.clone(),
+                    source,
+                }
+            })?;
+        let present: HashSet<&str> = index.weight_map.keys().map(|s| s.as_str()).collect();
+        let names = check_tensor_names(&present, config)?;
+        // group the names by shard, so that each shard is mapped once
+        let mut shards: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for name in names {
+            shards
+                .entry(index.weight_map[name].as_str())
+                .or_default()
+                .push(name);
+        }
+        let mut tensors = HashMap::new();
+        for (file, names) in shards {
+            let path = model_dir.join(file);
+            let mmap = map_file(&path)?;
+            let safetensor = deserialize(&mmap, &path)?;
+            tensors.extend(load_tensors(&safetensor, Some(&mmap), &names, config)?);
+        }
+        Self::from_weights(tensors, config)
+    }
+
+    // Load an in-memory checkpoint, copying every tensor
+    #[allow(unused)]
+    pub fn from_safetensors(
+        safetensor: &SafeTensors,
+        config: &LlamaConfigJson,
+    ) -> Result<Self, LoadError> {
+        Self::load_safetensors(safetensor, None, config)
+    }
+
+    fn load_safetensors(
+        safetensor: &SafeTensors,
+        mmap: Option<&Arc<Mmap>>,
+        config: &LlamaConfigJson,
+    ) -> Result<Self, LoadError> {
+        config.validate()?;
+        let present: HashSet<&str> = safetensor.names().into_iter().map(|s| s.as_str()).collect();
+        let names = check_tensor_names(&present, config)?;
+        Self::from_weights(load_tensors(safetensor, mmap, &names, config)?, config)
+    }
+
+    // Assemble the parameters from tensors keyed by their HF names
+    pub fn from_tensors(
+        tensors: HashMap<String, Tensor<T>>,
+        config: &LlamaConfigJson,
+    ) -> Result<Self, LoadError> {
+        let weights = tensors
+            .into_iter()
+            .map(|(name, tensor)| (name, Weight::Dense(tensor)))
+            .collect();
+        Self::from_weights(weights, config)
+    }
+
+    // The same from weights, of which the projections may be quantized
+    fn from_weights(
+        mut tensors: HashMap<String, Weight<T>>,
+        config: &LlamaConfigJson,
+    ) -> Result<Self, LoadError> {
+        config.validate()?;
+        check_tensor_shapes(&tensors, config)?;
+        let mut get_weights = |name: &str| {
+            (0..config.num_hidden_layers)
+                .map(|layer| take_weight(&mut tensors, &layer_tensor_name(layer, name)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let wq = get_weights("self_attn.q_proj.weight")?;
+        let wk = get_weights("self_attn.k_proj.weight")?;
+        let wv = get_weights("self_attn.v_proj.weight")?;
+        let wo = get_weights("self_attn.o_proj.weight")?;
+        let w_up = get_weights("mlp.up_proj.weight")?;
+        let w_gate = get_weights("mlp.gate_proj.weight")?;
+        let w_down = get_weights("mlp.down_proj.weight")?;
+        let mut get_layers = |name: &str| {
+            (get_weights(name)?.into_iter().enumerate())
+                .map(|(layer, w)| into_dense(&layer_tensor_name(layer, name), w))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let rms_att_w = get_layers("input_layernorm.weight")?;
+        let rms_ffn_w = get_layers("post_attention_layernorm.weight")?;
+        let rms_out_w = take_tensor(&mut tensors, "model.norm.weight")?;
+
+        let (embedding_table, lm_head) = if config.tie_word_embeddings {
+            // a tied checkpoint stores the shared table under either name
+            let embedding_table = match tensors.remove(EMBED_TOKENS) {
+                Some(weight) => into_dense(EMBED_TOKENS, weight)?,
+                None => take_tensor(&mut tensors, LM_HEAD)?,
+            };
+            // tied tables share one buffer
+            (embedding_table.clone(), embedding_table)
+        } else {
+            let embedding_table = take_tensor(&mut tensors, EMBED_TOKENS)?;
+            let lm_head = take_tensor(&mut tensors, LM_HEAD)?;
+            (embedding_table, lm_head)
+        };
+
+        Ok(LLamaParams {
+            embedding_table,
+            rms_att_w,
+            wq,
+            wk,
+            wv,
+            wo,
+            rms_ffn_w,
+            w_up,
+            w_gate,
+            w_down,
+            rms_out_w,
+            lm_head: Weight::Dense(lm_head),
+        })
+    }
+
+    // Re-encode the projection weights in the formats chosen by `options`
+    pub fn into_formats(self, options: &LoadOptions) -> Self {
+        // the embedding table is only gathered from, so it always stays dense
+        let convert = |weights: Vec<Weight<T>>, name: &str| {
+            weights
+                .into_iter()
+                .enumerate()
+                .map(|(layer, w)| {
+                    let name = layer_tensor_name(layer, name);
+                    w.into_format(options.format_of(&name, options.linear))
+                })
+                .collect()
+        };
         LLamaParams {
-            wq: vec![
-                get_tensor("model.layers.0.self_attn.q_proj.weight").unwrap(),
-                get_tensor("model.layers.1.self_attn.q_proj.weight").unwrap(),
-            ],
-            wk: vec![
-                get_tensor("model.layers.0.self_attn.k_proj.weight").unwrap(),
-                get_tensor("model.layers.1.self_attn.k_proj.weight").unwrap(),
-            ],
-            wv: vec![
-                get_tensor("model.layers.0.self_attn.v_proj.weight").unwrap(),
-                get_tensor("model.layers.1.self_attn.v_proj.weight").unwrap(),
-            ],
-            wo: vec![
-                get_tensor("model.layers.0.self_attn.o_proj.weight").unwrap(),
-                get_tensor("model.layers.1.self_attn.o_proj.weight").unwrap(),
-            ],
-            w_up: vec![
-                get_tensor("model.layers.0.mlp.up_proj.weight").unwrap(),
-                get_tensor("model.layers.1.mlp.up_proj.weight").unwrap(),
-            ],
-            w_gate: vec![
-                get_tensor("model.layers.0.mlp.gate_proj.weight").unwrap(),
-                get_tensor("model.layers.1.mlp.gate_proj.weight").unwrap(),
-            ],
-            w_down: vec![
-                get_tensor("model.layers.0.mlp.down_proj.weight").unwrap(),
-                get_tensor("model.layers.1.mlp.down_proj.weight").unwrap(),
-            ],
-            rms_att_w: vec![
-                get_tensor("model.layers.0.input_layernorm.weight").unwrap(),
-                get_tensor("model.layers.1.input_layernorm.weight").unwrap(),
-            ],
-            rms_ffn_w: vec![
-                get_tensor("model.layers.0.post_attention_layernorm.weight").unwrap(),
-                get_tensor("model.layers.1.post_attention_layernorm.weight").unwrap(),
-            ],
-            rms_out_w: get_tensor("model.norm.weight").unwrap(),
-            lm_head: get_tensor("lm_head.weight").unwrap(),
-            embedding_table: get_tensor("lm_head.weight").unwrap(),
+            wq: convert(self.wq, "self_attn.q_proj.weight"),
+            wk: convert(self.wk, "self_attn.k_proj.weight"),
+            wv: convert(self.wv, "self_attn.v_proj.weight"),
+            wo: convert(self.wo, "self_attn.o_proj.weight"),
+            w_up: convert(self.w_up, "mlp.up_proj.weight"),
+            w_gate: convert(self.w_gate, "mlp.gate_proj.weight"),
+            w_down: convert(self.w_down, "mlp.down_proj.weight"),
+            lm_head: self
+                .lm_head
+                .into_format(options.format_of(LM_HEAD, options.lm_head)),
+            ..self
         }
     }
+
+    // Whether lm_head shares the embedding table's buffer
+    pub fn is_tied(&self) -> bool {
+        match &self.lm_head {
+            Weight::Dense(lm_head) => {
+                lm_head.data().as_ptr() == self.embedding_table.data().as_ptr()
+                    && lm_head.shape() == self.embedding_table.shape()
+            }
+            _ => false,
+        }
+    }
+
+    // Visit every tensor with its HF name and whether it is an attention or
+    // mlp projection. A tied lm_head is left out.
+    pub fn for_each_weight(&self, mut f: impl FnMut(String, &Weight<T>, bool)) {
+        let dense = |t: &Tensor<T>| Weight::Dense(t.clone());
+        f(
+            EMBED_TOKENS.to_string(),
+            &dense(&self.embedding_table),
+            false,
+        );
+        for layer in 0..self.wq.len() {
+            let name = |name: &str| layer_tensor_name(layer, name);
+            f(
+                name("input_layernorm.weight"),
+                &dense(&self.rms_att_w[layer]),
+                false,
+            );
+            f(name("self_attn.q_proj.weight"), &self.wq[layer], true);
+            f(name("self_attn.k_proj.weight"), &self.wk[layer], true);
+            f(name("self_attn.v_proj.weight"), &self.wv[layer], true);
+            f(name("self_attn.o_proj.weight"), &self.wo[layer], true);
+            f(
+                name("post_attention_layernorm.weight"),
+                &dense(&self.rms_ffn_w[layer]),
+                false,
+            );
+            f(name("mlp.up_proj.weight"), &self.w_up[layer], true);
+            f(name("mlp.gate_proj.weight"), &self.w_gate[layer], true);
+            f(name("mlp.down_proj.weight"), &self.w_down[layer], true);
+        }
+        f(
+            "model.norm.weight".to_string(),
+            &dense(&self.rms_out_w),
+            false,
+        );
+        if !self.is_tied() {
+            f(LM_HEAD.to_string(), &self.lm_head, false);
+        }
+    }
+
+    // Write the weights to one safetensors file under their HF names. Dense
+    // tensors are stored as `dtype`; quantized weights are dequantized unless
+    // `int8_linear` stores the projections as int8.
+    pub fn save_safetensors(
+        &self,
+        path: &Path,
+        dtype: Dtype,
+        int8_linear: bool,
+    ) -> Result<(), SaveError> {
+        if convert_from_f32(dtype, &[]).is_none() {
+            return Err(SaveError::UnsupportedDtype(dtype));
+        }
+        let mut buffers: Vec<(String, Dtype, Vec<usize>, Vec<u8>)> = vec![];
+        self.for_each_weight(|name, weight, linear| {
+            if int8_linear && linear {
+                let quantized;
+                let q = match weight {
+                    Weight::Int8(q) => q,
+                    Weight::Dense(t) => {
+                        quantized = Q8Tensor::quantize(t);
+                        &quantized
+                    }
+                    w => {
+                        quantized = Q8Tensor::quantize(&w.dequantize());
+                        &quantized
+                    }
+                };
+                let scales = convert_from_f32(Dtype::F32, q.scales()).unwrap();
+                buffers.push((
+                    format!("{name}{SCALE_SUFFIX}"),
+                    Dtype::F32,
+                    vec![q.scales().len()],
+                    scales,
+                ));
+                let data = q.data().iter().map(|&x| x as u8).collect();
+                buffers.push((name, Dtype::I8, q.shape().clone(), data));
+            } else {
+                let t = weight.dequantize();
+                let data = convert_from_f32(dtype, t.data()).unwrap();
+                buffers.push((name, dtype, t.shape().clone(), data));
+            }
+        });
+
+        let views = buffers.iter().map(|(name, dtype, shape, data)| {
+            (name, TensorView::new(*dtype, shape.clone(), data).unwrap())
+        });
+        // the metadata transformers expects of PyTorch checkpoints; int8 weights
+        // with their scales aren't one
+        let metadata = match int8_linear {
+            false => HashMap::from([("format".to_string(), "pt".to_string())]),
+            true => HashMap::from([("format".to_string(), "learning-lm-rs-int8".to_string())]),
+        };
+        let bytes = safetensors::serialize(views, &Some(metadata)).map_err(SaveError::Serialize)?;
+        write_atomic(path, &bytes)
+    }
+}
+
+// Write `bytes` to a temporary file next to `path` and rename it into place,
+// so that a failure leaves the old file, and a model mapping the old file
+// keeps its pages instead of seeing it truncated
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), SaveError> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{file_name}.{}.tmp", std::process::id()));
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    result.map_err(|source| {
+        let _ = std::fs::remove_file(&tmp_path);
+        SaveError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    })
+}
+
+// Build a tiny checkpoint in memory, leaving out the `skip` tensors.
+// Every element of a tensor is set to its index in `expected_tensor_names`.
+// Only the float dtypes the model can be saved in are supported.
+#[cfg(test)]
+pub fn tiny_checkpoint(
+    config: &LlamaConfigJson,
+    dtype: Dtype,
+    skip: &[&str],
+) -> Result<Vec<u8>, SaveError> {
+    let buffers = expected_tensor_names(config)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, name)| !skip.contains(&name.as_str()))
+        .map(|(i, name)| {
+            let shape = tiny_shape(&name, config.head_dim());
+            let len: usize = shape.iter().product();
+            let bytes = convert_from_f32(dtype, &vec![i as f32; len])
+                .ok_or(SaveError::UnsupportedDtype(dtype))?;
+            Ok((name, shape, bytes))
+        })
+        .collect::<Result<Vec<_>, SaveError>>()?;
+    let views = buffers
+        .iter()
+        .map(|(name, shape, bytes)| (name, TensorView::new(dtype, shape.clone(), bytes).unwrap()));
+    Ok(safetensors::serialize(views, &None).unwrap())
+}
+
+// The shapes of the tiny_config tensors, written out instead of taken from
+// expected_shape, so that the fixtures check it
+#[cfg(test)]
+pub fn tiny_shape(name: &str, head_dim: usize) -> Vec<usize> {
+    match name.rsplit('.').nth(1).unwrap_or_default() {
+        "embed_tokens" | "lm_head" => vec![10, 8],
+        // two q heads and one kv head
+        "q_proj" => vec![2 * head_dim, 8],
+        "k_proj" | "v_proj" => vec![head_dim, 8],
+        "o_proj" => vec![8, 2 * head_dim],
+        "up_proj" | "gate_proj" => vec![16, 8],
+        "down_proj" => vec![8, 16],
+        _ => vec![8],
+    }
+}
+
+#[cfg(test)]
+pub fn tiny_config(n_layers: usize, tie_word_embeddings: bool) -> LlamaConfigJson {
+    serde_json::from_value(serde_json::json!({
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+        "hidden_size": 8,
+        "intermediate_size": 16,
+        "max_position_embeddings": 32,
+        "num_attention_heads": 2,
+        "num_hidden_layers": n_layers,
+        "num_key_value_heads": 1,
+        "vocab_size": 10,
+        "torch_dtype": "float32",
+        "tie_word_embeddings": tie_word_embeddings,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_load_all_layers() {
+    let config = tiny_config(3, true);
+    let buffer = tiny_checkpoint(&config, Dtype::F32, &[]).unwrap();
+    let params =
+        LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&buffer).unwrap(), &config)
+            .unwrap();
+    assert_eq!(params.wq.len(), 3);
+    assert_eq!(params.w_down.len(), 3);
+    // layer 2 q_proj is the 21st expected tensor
+    assert_eq!(params.wq[2].as_dense().unwrap().data()[0], 20.);
+    assert_eq!(params.rms_out_w.data()[0], 28.);
+}
+
+#[test]
+fn test_missing_layer_tensor() {
+    let config = tiny_config(3, true);
+    let buffer = tiny_checkpoint(
+        &config,
+        Dtype::F32,
+        &["model.layers.2.mlp.down_proj.weight"],
+    )
+    .unwrap();
+    let result =
+        LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&buffer).unwrap(), &config);
+    assert!(matches!(
+        result,
+        Err(LoadError::MissingTensor(names)) if names == ["model.layers.2.mlp.down_proj.weight"]
+    ));
+}
+
+#[test]
+fn test_vocab_shape_mismatch() {
+    let config = tiny_config(1, true);
+    let buffer = tiny_checkpoint(&config, Dtype::F32, &[]).unwrap();
+    let config = LlamaConfigJson {
+        vocab_size: 12,
+        ..config
+    };
+    let result =
+        LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&buffer).unwrap(), &config);
+    let Err(LoadError::ShapeMismatch(mismatches)) = result else {
+        panic!("expected a shape mismatch");
+    };
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].name, EMBED_TOKENS);
+    assert_eq!(mismatches[0].expected, [12, 8]);
+    assert_eq!(mismatches[0].actual, [10, 8]);
+}
+
+#[test]
+fn test_projection_shape_mismatch() {
+    let config = tiny_config(2, true);
+    let buffer = tiny_checkpoint(&config, Dtype::F32, &[]).unwrap();
+    // the checkpoint has one kv head of dimension 4
+    let config = LlamaConfigJson {
+        num_key_value_heads: 2,
+        ..config
+    };
+    let result =
+        LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&buffer).unwrap(), &config);
+    let Err(error) = result else {
+        panic!("expected a shape mismatch");
+    };
+    let LoadError::ShapeMismatch(mismatches) = &error else {
+        panic!("expected a shape mismatch, got {error}");
+    };
+    // every k and v projection is reported, in checkpoint order
+    let names: Vec<&str> = mismatches.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "model.layers.0.self_attn.k_proj.weight",
+            "model.layers.0.self_attn.v_proj.weight",
+            "model.layers.1.self_attn.k_proj.weight",
+            "model.layers.1.self_attn.v_proj.weight",
+        ]
+    );
+    assert!(error
+        .to_string()
+        .contains("model.layers.1.self_attn.v_proj.weight: expected [8, 8], found [4, 8]"));
+}
+
+#[test]
+fn test_untied_embeddings() {
+    let config = tiny_config(1, false);
+    let buffer = tiny_checkpoint(&config, Dtype::F32, &[]).unwrap();
+    let params =
+        LLamaParams::<f32>::from_safetensors(&SafeTensors::deserialize(&buffer).unwrap(), &config)
+            .unwrap();
+    assert_eq!(params.embedding_table.data()[0], 0.);
+    assert_eq!(params.lm_head.as_dense().unwrap().data()[0], 11.);
+}
+
+#[test]
+fn test_tied_embeddings_share_buffer() {
+    let config = tiny_config(1, true);
+    // either name may hold the shared table
+    for (skip, value) in [(LM_HEAD, 0.), (EMBED_TOKENS, 11.)] {
+        let buffer = tiny_checkpoint(&tiny_config(1, false), Dtype::F32, &[skip]).unwrap();
+        let params = LLamaParams::<f32>::from_safetensors(
+            &SafeTensors::deserialize(&buffer).unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(params.embedding_table.data()[0], value);
+        assert_eq!(
+            params.embedding_table.data().as_ptr(),
+            params.lm_head.as_dense().unwrap().data().as_ptr()
+        );
+    }
+}
+
+#[test]
+fn test_convert_to_f32() {
+    let bf16_bytes = [0x80, 0x3f, 0x00, 0xc0]; // 1.0, -2.0
+    assert_eq!(
+        convert_to_f32(Dtype::BF16, &bf16_bytes),
+        Some(vec![1., -2.])
+    );
+    let f16_bytes = [0x00, 0x3c, 0x00, 0xb8]; // 1.0, -0.5
+    assert_eq!(convert_to_f32(Dtype::F16, &f16_bytes), Some(vec![1., -0.5]));
+    assert_eq!(
+        convert_to_f32(Dtype::F64, &0.25f64.to_le_bytes()),
+        Some(vec![0.25])
+    );
+    assert_eq!(convert_to_f32(Dtype::I32, &[0; 4]), None);
+}
+
+#[test]
+fn test_load_half_precision() {
+    let config = tiny_config(1, false);
+    for dtype in [Dtype::F16, Dtype::BF16, Dtype::F64] {
+        let buffer = tiny_checkpoint(&config, dtype, &[]).unwrap();
+        let params = LLamaParams::<f32>::from_safetensors(
+            &SafeTensors::deserialize(&buffer).unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(params.wq[0].as_dense().unwrap().data()[0], 2.);
+        assert_eq!(params.lm_head.as_dense().unwrap().data()[0], 11.);
+    }
+}
+
+#[test]
+fn test_reject_integer_dtype() {
+    use crate::model::Llama;
+    // a checkpoint with one k projection stored as u8
+    let config = tiny_config(1, false);
+    let k_proj = "model.layers.0.self_attn.k_proj.weight";
+    let buffer = tiny_checkpoint(&config, Dtype::F32, &[]).unwrap();
+    let tensors = SafeTensors::deserialize(&buffer).unwrap();
+    let u8_data = vec![1u8; 4 * 8];
+    let views = tensors.tensors().into_iter().map(|(name, view)| {
+        let view = if name == k_proj {
+            TensorView::new(Dtype::U8, vec![4, 8], &u8_data).unwrap()
+        } else {
+            view
+        };
+        (name, view)
+    });
+    let buffer = safetensors::serialize(views, &None).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("learning-lm-rs-u8-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("config.json"),
+        serde_json::to_vec(&config).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(dir.join("model.safetensors"), buffer).unwrap();
+    let result = Llama::<f32>::from_safetensors(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(matches!(
+        result,
+        Err(LoadError::UnsupportedDtype { name, dtype: Dtype::U8 }) if name == k_proj
+    ));
+}
+
+#[test]
+fn test_load_sharded() {
+    let config = tiny_config(2, false);
+    let buffer = tiny_checkpoint(&config, Dtype::F32, &[]).unwrap();
+    let full = SafeTensors::deserialize(&buffer).unwrap();
+    let dir = std::env::temp_dir().join(format!("learning-lm-rs-shards-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let (first, second): (Vec<_>, Vec<_>) = full
+        .tensors()
+        .into_iter()
+        .partition(|(name, _)| name.starts_with("model.layers.1."));
+    let mut weight_map = HashMap::new();
+    for (file, tensors) in [
+        ("model-00001-of-00002.safetensors", first),
+        ("model-00002-of-00002.safetensors", second),
+    ] {
+        for (name, _) in &tensors {
+            weight_map.insert(name.clone(), file);
+        }
+        safetensors::serialize_to_file(tensors, &None, &dir.join(file)).unwrap();
+    }
+    let index = serde_json::json!({ "metadata": {}, "weight_map": weight_map });
+    std::fs::write(dir.join("model.safetensors.index.json"), index.to_string()).unwrap();
+
+    let sharded = LLamaParams::<f32>::from_model_dir(&dir, &config);
+
+    // shards outside the model directory are refused
+    for file in [
+        "../model-00001-of-00002.safetensors",
+        "/etc/passwd",
+        "a/b.safetensors",
+        "",
+    ] {
+        let mut weight_map = weight_map.clone();
+        weight_map.insert("model.norm.weight".to_string(), file);
+        let index = serde_json::json!({ "metadata": {}, "weight_map": weight_map });
+        std::fs::write(dir.join("model.safetensors.index.json"), index.to_string()).unwrap();
+        let result = LLamaParams::<f32>::from_model_dir(&dir, &config);
+        assert!(
+            matches!(result, Err(LoadError::BadIndex { .. })),
+            "{file:?}"
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    let sharded = sharded.unwrap();
+    let single = LLamaParams::<f32>::from_safetensors(&full, &config).unwrap();
+    assert_eq!(
+        sharded.wq[1].as_dense().unwrap().data(),
+        single.wq[1].as_dense().unwrap().data()
+    );
+    assert_eq!(
+        sharded.w_down[0].as_dense().unwrap().data(),
+        single.w_down[0].as_dense().unwrap().data()
+    );
+    assert_eq!(
+        sharded.lm_head.as_dense().unwrap().data(),
+        single.lm_head.as_dense().unwrap().data()
+    );
+    assert_eq!(
+        sharded.embedding_table.data(),
+        single.embedding_table.data()
+    );
 }
@@ -0,0 +1,306 @@
+use crate::tensor::{Float, Tensor};
+use half::f16;
+
+// How a projection weight is stored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeightFormat {
+    // the model's element type
+    #[default]
+    Dense,
+    // int8 values with one f32 scale per output row
+    Int8,
+    // 4-bit blocks of 32 values with an f16 scale: w = (q - 8) * d
+    Q4_0,
+    // 4-bit blocks of 32 values with an f16 scale and min: w = q * d + m
+    Q4_1,
+}
+
+// Int8 matrix with a symmetric scale per row: w[i][j] ≈ data[i][j] * scales[i]
+pub struct Q8Tensor {
+    data: Vec<i8>,
+    scales: Vec<f32>,
+    shape: Vec<usize>,
+}
+
+impl Q8Tensor {
+    pub fn quantize<T: Float>(w: &Tensor<T>) -> Self {
+        assert!(w.shape().len() == 2);
+        let (rows, cols) = (w.shape()[0], w.shape()[1]);
+        let mut data = Vec::with_capacity(rows * cols);
+        let mut scales = Vec::with_capacity(rows);
+        for row in w.data().chunks_exact(cols) {
+            let max = row.iter().fold(0f32, |m, x| m.max(x.to_f32().abs()));
+            let scale = max / 127.;
+            let inv_scale = if scale > 0. { 1. / scale } else { 0. };
+            data.extend(row.iter().map(|x| (x.to_f32() * inv_scale).round() as i8));
+            scales.push(scale);
+        }
+        Q8Tensor {
+            data,
+            scales,
+            shape: w.shape().clone(),
+        }
+    }
+
+    // Values and row scales as `quantize` makes them, e.g. read back from a file
+    pub fn from_parts(data: Vec<i8>, scales: Vec<f32>, shape: Vec<usize>) -> Self {
+        assert!(shape.len() == 2 && scales.len() == shape[0]);
+        assert_eq!(data.len(), shape[0] * shape[1]);
+        Q8Tensor {
+            data,
+            scales,
+            shape,
+        }
+    }
+
+    pub fn shape(&self) -> &Vec<usize> {
+        &self.shape
+    }
+
+    pub fn data(&self) -> &[i8] {
+        &self.data
+    }
+
+    pub fn scales(&self) -> &[f32] {
+        &self.scales
+    }
+
+    // The quantized values and the scale of row i
+    pub fn row(&self, i: usize) -> (&[i8], f32) {
+        let cols = self.shape[1];
+        (&self.data[i * cols..][..cols], self.scales[i])
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let data = (0..self.shape[0])
+            .flat_map(|i| {
+                let (row, scale) = self.row(i);
+                row.iter().map(move |&q| q as f32 * scale)
+            })
+            .collect();
+        Tensor::new(data, &self.shape)
+    }
+}
+
+pub const Q4_BLOCK: usize = 32;
+
+// 4-bit matrix quantized in blocks of Q4_BLOCK values along each row. The last
+// block of a row is zero-padded. Byte j of a block packs value j in its low
+// nibble and value j + 16 in its high nibble.
+pub struct Q4Tensor {
+    qs: Vec<u8>,            // Q4_BLOCK / 2 bytes per block
+    scales: Vec<f16>,       // one per block
+    mins: Option<Vec<f16>>, // one per block for Q4_1
+    shape: Vec<usize>,
+}
+
+impl Q4Tensor {
+    pub fn quantize<T: Float>(w: &Tensor<T>, with_min: bool) -> Self {
+        assert!(w.shape().len() == 2);
+        let (rows, cols) = (w.shape()[0], w.shape()[1]);
+        let n_blocks = rows * cols.div_ceil(Q4_BLOCK);
+        let mut qs = Vec::with_capacity(n_blocks * Q4_BLOCK / 2);
+        let mut scales = Vec::with_capacity(n_blocks);
+        let mut mins = Vec::with_capacity(if with_min { n_blocks } else { 0 });
+        let mut block = [0f32; Q4_BLOCK];
+        for row in w.data().chunks_exact(cols) {
+            for chunk in row.chunks(Q4_BLOCK) {
+                block.fill(0.);
+                for (x, y) in block.iter_mut().zip(chunk) {
+                    *x = y.to_f32();
+                }
+                let (d, m) = if with_min {
+                    let min = block.iter().fold(f32::INFINITY, |a, &b| a.min(b));
+                    let max = block.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+                    (f16::from_f32((max - min) / 15.), f16::from_f32(min))
+                } else {
+                    // the value of largest magnitude maps to q = 0
+                    let max = block
+                        .iter()
+                        .fold(0f32, |a, &b| if b.abs() > a.abs() { b } else { a });
+                    let d = f16::from_f32(max / -8.);
+                    (d, f16::from_f32(-8. * d.to_f32()))
+                };
+                // quantize against the rounded scale and min that are stored
+                let (d, m) = (d.to_f32(), m.to_f32());
+                let inv_d = if d != 0. { 1. / d } else { 0. };
+                let q = |x: f32| ((x - m) * inv_d).round().clamp(0., 15.) as u8;
+                let half = Q4_BLOCK / 2;
+                qs.extend((0..half).map(|j| q(block[j]) | (q(block[j + half]) << 4)));
+                scales.push(f16::from_f32(d));
+                if with_min {
+                    mins.push(f16::from_f32(m));
+                }
+            }
+        }
+        Q4Tensor {
+            qs,
+            scales,
+            mins: with_min.then_some(mins),
+            shape: w.shape().clone(),
+        }
+    }
+
+    pub fn shape(&self) -> &Vec<usize> {
+        &self.shape
+    }
+
+    #[allow(unused)]
+    pub fn format(&self) -> WeightFormat {
+        match self.mins {
+            Some(_) => WeightFormat::Q4_1,
+            None => WeightFormat::Q4_0,
+        }
+    }
+
+    // Scale and offset of a block: w = q * d + m
+    fn block_params(&self, block: usize) -> (f32, f32) {
+        let d = self.scales[block].to_f32();
+        match &self.mins {
+            Some(mins) => (d, mins[block].to_f32()),
+            None => (d, -8. * d),
+        }
+    }
+
+    // Dot product of row i with `x`, dequantizing one block at a time
+    pub fn dot_row(&self, i: usize, x: &[f32]) -> f32 {
+        let cols = self.shape[1];
+        assert!(x.len() == cols);
+        let blocks_per_row = cols.div_ceil(Q4_BLOCK);
+        let half = Q4_BLOCK / 2;
+        let mut sum = 0.;
+        for (b, x) in x.chunks(Q4_BLOCK).enumerate() {
+            let block = i * blocks_per_row + b;
+            let qs = &self.qs[block * half..][..half];
+            // the low nibbles hold the first half of the block, the high nibbles the rest
+            let (lo, hi) = x.split_at(x.len().min(half));
+            let mut qx = 0.;
+            for (q, x) in qs.iter().zip(lo) {
+                qx += (q & 0x0f) as f32 * x;
+            }
+            for (q, x) in qs.iter().zip(hi) {
+                qx += (q >> 4) as f32 * x;
+            }
+            let (d, m) = self.block_params(block);
+            sum += d * qx + m * x.iter().sum::<f32>();
+        }
+        sum
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let (rows, cols) = (self.shape[0], self.shape[1]);
+        let blocks_per_row = cols.div_ceil(Q4_BLOCK);
+        let half = Q4_BLOCK / 2;
+        let mut data = Vec::with_capacity(rows * cols);
+        let mut values = [0f32; Q4_BLOCK];
+        for block in 0..rows * blocks_per_row {
+            let (d, m) = self.block_params(block);
+            for (j, q) in self.qs[block * half..][..half].iter().enumerate() {
+                values[j] = (q & 0x0f) as f32 * d + m;
+                values[j + half] = (q >> 4) as f32 * d + m;
+            }
+            // drop the padding of the last block in a row
+            let len = Q4_BLOCK.min(cols - block % blocks_per_row * Q4_BLOCK);
+            data.extend_from_slice(&values[..len]);
+        }
+        Tensor::new(data, &self.shape)
+    }
+}
+
+// A projection weight (out_features, in_features), dense or quantized
+pub enum Weight<T> {
+    Dense(Tensor<T>),
+    Int8(Q8Tensor),
+    Q4(Q4Tensor),
+}
+
+impl<T: Float> Weight<T> {
+    pub fn new(tensor: Tensor<T>, format: WeightFormat) -> Self {
+        match format {
+            WeightFormat::Dense => Weight::Dense(tensor),
+            WeightFormat::Int8 => Weight::Int8(Q8Tensor::quantize(&tensor)),
+            WeightFormat::Q4_0 => Weight::Q4(Q4Tensor::quantize(&tensor, false)),
+            WeightFormat::Q4_1 => Weight::Q4(Q4Tensor::quantize(&tensor, true)),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn format(&self) -> WeightFormat {
+        match self {
+            Weight::Dense(_) => WeightFormat::Dense,
+            Weight::Int8(_) => WeightFormat::Int8,
+            Weight::Q4(q) => q.format(),
+        }
+    }
+
+    pub fn shape(&self) -> &Vec<usize> {
+        match self {
+            Weight::Dense(t) => t.shape(),
+            Weight::Int8(q) => q.shape(),
+            Weight::Q4(q) => q.shape(),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn as_dense(&self) -> Option<&Tensor<T>> {
+        match self {
+            Weight::Dense(t) => Some(t),
+            _ => None,
+        }
+    }
+
+    // The weight's values in f32
+    pub fn dequantize(&self) -> Tensor<f32> {
+        match self {
+            Weight::Dense(t) => {
+                Tensor::new(t.data().iter().map(|x| x.to_f32()).collect(), t.shape())
+            }
+            Weight::Int8(q) => q.dequantize(),
+            Weight::Q4(q) => q.dequantize(),
+        }
+    }
+
+    // Re-encode a dense weight in `format`; quantized weights are kept as they are
+    pub fn into_format(self, format: WeightFormat) -> Self {
+        match self {
+            Weight::Dense(t) => Weight::new(t, format),
+            w => w,
+        }
+    }
+}
+
+#[test]
+fn test_q8_round_trip() {
+    let w = Tensor::<f32>::new(vec![1., -0.5, 0.25, 0., 0., 0., -3., 2., 1.], &[3, 3]);
+    let q = Q8Tensor::quantize(&w);
+    assert_eq!(q.row(0), (&[127i8, -64, 32][..], 1. / 127.));
+    // an all-zero row gets a zero scale instead of NaNs
+    assert_eq!(q.row(1), (&[0i8, 0, 0][..], 0.));
+    // the error of every element is at most half a quantization step
+    for (i, (x, y)) in w.data().iter().zip(q.dequantize().data()).enumerate() {
+        assert!((x - y).abs() <= q.row(i / 3).1 / 2. + 1e-7);
+    }
+}
+
+#[test]
+fn test_q4_round_trip() {
+    // 40 columns, so each row ends with a zero-padded block
+    let data: Vec<f32> = (0..80).map(|i| ((i * 7 % 23) as f32 - 11.) / 10.).collect();
+    let w = Tensor::<f32>::new(data, &[2, 40]);
+    let x: Vec<f32> = (0..40).map(|i| (i % 5) as f32 - 2.).collect();
+    for with_min in [false, true] {
+        let q = Q4Tensor::quantize(&w, with_min);
+        let dequantized = q.dequantize();
+        assert_eq!(dequantized.shape(), w.shape());
+        // values in [-1.1, 1.1] are off by at most one step of a 16-level grid
+        for (a, b) in w.data().iter().zip(dequantized.data()) {
+            assert!((a - b).abs() <= 2.2 / 15. + 1e-3, "{a} {b}");
+        }
+        // the kernel computes exactly the dot product with the dequantized row
+        for i in 0..2 {
+            let row = &dequantized.data()[i * 40..][..40];
+            let expected: f32 = row.iter().zip(&x).map(|(a, b)| a * b).sum();
+            assert!((q.dot_row(i, &x) - expected).abs() < 1e-4);
+        }
+    }
+}
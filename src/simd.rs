@@ -0,0 +1,747 @@
+// Vectorized f32 kernels for the hot loops of the operators. The best
+// instruction set the CPU supports is picked at runtime; the scalar kernels
+// are the fallback and the reference the SIMD paths are tested against.
+use std::sync::OnceLock;
+
+// Instruction sets there are kernels for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Isa {
+    Scalar,
+    Avx2,
+    Avx512,
+    Neon,
+}
+
+impl Isa {
+    // In order of preference
+    pub const ALL: [Isa; 4] = [Isa::Avx512, Isa::Avx2, Isa::Neon, Isa::Scalar];
+
+    // Whether this CPU can run the kernels of `self`
+    pub fn is_supported(self) -> bool {
+        match self {
+            Isa::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx2 => is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma"),
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    // The kernels of `self`, if this CPU can run them
+    pub fn kernels(self) -> Option<&'static Kernels> {
+        if !self.is_supported() {
+            return None;
+        }
+        match self {
+            Isa::Scalar => Some(&scalar::KERNELS),
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx2 => Some(&avx2::KERNELS),
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx512 => Some(&avx512::KERNELS),
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => Some(&neon::KERNELS),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+// The kernels of the best instruction set this CPU supports
+pub fn kernels() -> &'static Kernels {
+    static BEST: OnceLock<&'static Kernels> = OnceLock::new();
+    BEST.get_or_init(|| Isa::ALL.iter().find_map(|isa| isa.kernels()).unwrap())
+}
+
+// One implementation of every kernel. The SIMD ones are only handed out by
+// Isa::kernels after the CPU features they need have been detected.
+pub struct Kernels {
+    dot: fn(&[f32], &[f32]) -> f32,
+    scale_mul: fn(&mut [f32], &[f32], &[f32], f32),
+    silu_mul: fn(&mut [f32], &[f32]),
+    max: fn(&[f32]) -> f32,
+    exp_sum: fn(&mut [f32], f32) -> f32,
+    scale: fn(&mut [f32], f32),
+    axpy: fn(&mut [f32], f32, &[f32]),
+}
+
+impl Kernels {
+    // sum(a * b)
+    pub fn dot(&self, a: &[f32], b: &[f32]) -> f32 {
+        assert!(a.len() == b.len());
+        (self.dot)(a, b)
+    }
+
+    // y = x * w * s
+    pub fn scale_mul(&self, y: &mut [f32], x: &[f32], w: &[f32], s: f32) {
+        assert!(y.len() == x.len() && y.len() == w.len());
+        (self.scale_mul)(y, x, w, s)
+    }
+
+    // y = silu(x) * y
+    pub fn silu_mul(&self, y: &mut [f32], x: &[f32]) {
+        assert!(y.len() == x.len());
+        (self.silu_mul)(y, x)
+    }
+
+    // The largest element, -inf for an empty slice
+    pub fn max(&self, x: &[f32]) -> f32 {
+        (self.max)(x)
+    }
+
+    // x = exp(x - m), returning sum(x)
+    pub fn exp_sum(&self, x: &mut [f32], m: f32) -> f32 {
+        (self.exp_sum)(x, m)
+    }
+
+    // x = x * s
+    pub fn scale(&self, x: &mut [f32], s: f32) {
+        (self.scale)(x, s)
+    }
+
+    // y = y + a * x
+    pub fn axpy(&self, y: &mut [f32], a: f32, x: &[f32]) {
+        assert!(y.len() == x.len());
+        (self.axpy)(y, a, x)
+    }
+}
+
+mod scalar {
+    use super::Kernels;
+
+    pub static KERNELS: Kernels = Kernels {
+        dot,
+        scale_mul,
+        silu_mul,
+        max,
+        exp_sum,
+        scale,
+        axpy,
+    };
+
+    // Dot product with 8 independent sums, which the compiler can keep in one
+    // vector register
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+        let tail: f32 = (a_chunks.remainder().iter())
+            .zip(b_chunks.remainder())
+            .map(|(x, y)| x * y)
+            .sum();
+        let mut sums = [0f32; 8];
+        for (x, y) in a_chunks.zip(b_chunks) {
+            for l in 0..8 {
+                sums[l] += x[l] * y[l];
+            }
+        }
+        sums.iter().sum::<f32>() + tail
+    }
+
+    pub fn scale_mul(y: &mut [f32], x: &[f32], w: &[f32], s: f32) {
+        for ((y, x), w) in y.iter_mut().zip(x).zip(w) {
+            *y = x * w * s;
+        }
+    }
+
+    pub fn silu_mul(y: &mut [f32], x: &[f32]) {
+        for (y, x) in y.iter_mut().zip(x) {
+            *y *= x / (1. + (-x).exp());
+        }
+    }
+
+    pub fn max(x: &[f32]) -> f32 {
+        x.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b))
+    }
+
+    pub fn exp_sum(x: &mut [f32], m: f32) -> f32 {
+        let mut sum = 0.;
+        for x in x.iter_mut() {
+            *x = (*x - m).exp();
+            sum += *x;
+        }
+        sum
+    }
+
+    pub fn scale(x: &mut [f32], s: f32) {
+        x.iter_mut().for_each(|x| *x *= s);
+    }
+
+    pub fn axpy(y: &mut [f32], a: f32, x: &[f32]) {
+        for (y, x) in y.iter_mut().zip(x) {
+            *y += a * x;
+        }
+    }
+}
+
+// One register of f32 lanes. Implementations wrap the intrinsics of one
+// instruction set, so calling the methods is only sound on CPUs that have it.
+trait Vector: Copy {
+    const LANES: usize;
+    unsafe fn splat(x: f32) -> Self;
+    unsafe fn load(p: *const f32) -> Self;
+    unsafe fn store(self, p: *mut f32);
+    unsafe fn add(self, b: Self) -> Self;
+    unsafe fn mul(self, b: Self) -> Self;
+    unsafe fn div(self, b: Self) -> Self;
+    unsafe fn min(self, b: Self) -> Self;
+    unsafe fn max(self, b: Self) -> Self;
+    // self * b + c
+    unsafe fn mul_add(self, b: Self, c: Self) -> Self;
+    // to the nearest integer
+    unsafe fn round(self) -> Self;
+    // 2^self for integral self in [-126, 127]
+    unsafe fn exp2_int(self) -> Self;
+    unsafe fn sum(self) -> f32;
+    unsafe fn max_lane(self) -> f32;
+}
+
+// The kernels written once over Vector. They handle whole vectors and leave
+// the tail to the scalar kernels. Everything is inlined into the wrappers
+// vector_kernels! generates, which enable the target features.
+mod vector {
+    use super::{scalar, Vector};
+
+    // exp(x) = 2^n * exp(r) with r = x - n * ln 2 in [-ln 2 / 2, ln 2 / 2],
+    // where a degree 6 polynomial is within 2 ulp of exp(r). The input is
+    // clamped to where the result is a normal f32.
+    #[inline(always)]
+    unsafe fn exp<V: Vector>(x: V) -> V {
+        // ln 2 split in two, the first part exact in few enough bits that n * LN2_HI is exact
+        const LN2_HI: f32 = 355. / 512.;
+        const LN2_LO: f32 = -2.1219444e-4;
+        let x = x.max(V::splat(-87.3)).min(V::splat(88.3));
+        let n = x.mul(V::splat(std::f32::consts::LOG2_E)).round();
+        let r = n.mul_add(V::splat(-LN2_HI), x);
+        let r = n.mul_add(V::splat(-LN2_LO), r);
+        let mut p = V::splat(1. / 720.);
+        for c in [1. / 120., 1. / 24., 1. / 6., 0.5, 1., 1.] {
+            p = p.mul_add(r, V::splat(c));
+        }
+        p.mul(n.exp2_int())
+    }
+
+    #[inline(always)]
+    pub unsafe fn dot<V: Vector>(a: &[f32], b: &[f32]) -> f32 {
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let mut i = 0;
+        // four sums to hide the latency of the fused multiply-adds
+        let mut sums = [V::splat(0.); 4];
+        while i + 4 * V::LANES <= a.len() {
+            for (k, sum) in sums.iter_mut().enumerate() {
+                let j = i + k * V::LANES;
+                *sum = V::load(pa.add(j)).mul_add(V::load(pb.add(j)), *sum);
+            }
+            i += 4 * V::LANES;
+        }
+        let mut sum = sums[0].add(sums[1]).add(sums[2].add(sums[3]));
+        while i + V::LANES <= a.len() {
+            sum = V::load(pa.add(i)).mul_add(V::load(pb.add(i)), sum);
+            i += V::LANES;
+        }
+        sum.sum() + scalar::dot(&a[i..], &b[i..])
+    }
+
+    #[inline(always)]
+    pub unsafe fn scale_mul<V: Vector>(y: &mut [f32], x: &[f32], w: &[f32], s: f32) {
+        let (py, px, pw) = (y.as_mut_ptr(), x.as_ptr(), w.as_ptr());
+        let s_v = V::splat(s);
+        let mut i = 0;
+        while i + V::LANES <= y.len() {
+            let v = V::load(px.add(i)).mul(V::load(pw.add(i))).mul(s_v);
+            v.store(py.add(i));
+            i += V::LANES;
+        }
+        scalar::scale_mul(&mut y[i..], &x[i..], &w[i..], s);
+    }
+
+    #[inline(always)]
+    pub unsafe fn silu_mul<V: Vector>(y: &mut [f32], x: &[f32]) {
+        let (py, px) = (y.as_mut_ptr(), x.as_ptr());
+        let one = V::splat(1.);
+        let mut i = 0;
+        while i + V::LANES <= y.len() {
+            let x = V::load(px.add(i));
+            let sigmoid = one.div(one.add(exp(x.mul(V::splat(-1.)))));
+            V::load(py.add(i)).mul(x).mul(sigmoid).store(py.add(i));
+            i += V::LANES;
+        }
+        scalar::silu_mul(&mut y[i..], &x[i..]);
+    }
+
+    #[inline(always)]
+    pub unsafe fn max<V: Vector>(x: &[f32]) -> f32 {
+        let px = x.as_ptr();
+        let mut m = V::splat(f32::NEG_INFINITY);
+        let mut i = 0;
+        while i + V::LANES <= x.len() {
+            m = m.max(V::load(px.add(i)));
+            i += V::LANES;
+        }
+        m.max_lane().max(scalar::max(&x[i..]))
+    }
+
+    #[inline(always)]
+    pub unsafe fn exp_sum<V: Vector>(x: &mut [f32], m: f32) -> f32 {
+        let px = x.as_mut_ptr();
+        let m_v = V::splat(m);
+        let mut sum = V::splat(0.);
+        let mut i = 0;
+        while i + V::LANES <= x.len() {
+            let e = exp(V::load(px.add(i)).add(m_v.mul(V::splat(-1.))));
+            e.store(px.add(i));
+            sum = sum.add(e);
+            i += V::LANES;
+        }
+        sum.sum() + scalar::exp_sum(&mut x[i..], m)
+    }
+
+    #[inline(always)]
+    pub unsafe fn scale<V: Vector>(x: &mut [f32], s: f32) {
+        let px = x.as_mut_ptr();
+        let s_v = V::splat(s);
+        let mut i = 0;
+        while i + V::LANES <= x.len() {
+            V::load(px.add(i)).mul(s_v).store(px.add(i));
+            i += V::LANES;
+        }
+        scalar::scale(&mut x[i..], s);
+    }
+
+    #[inline(always)]
+    pub unsafe fn axpy<V: Vector>(y: &mut [f32], a: f32, x: &[f32]) {
+        let (py, px) = (y.as_mut_ptr(), x.as_ptr());
+        let a_v = V::splat(a);
+        let mut i = 0;
+        while i + V::LANES <= y.len() {
+            let v = V::load(px.add(i)).mul_add(a_v, V::load(py.add(i)));
+            v.store(py.add(i));
+            i += V::LANES;
+        }
+        scalar::axpy(&mut y[i..], a, &x[i..]);
+    }
+}
+
+// A `KERNELS` static with the kernels of `vector` instantiated for a Vector
+// type, each compiled with `features` enabled
+macro_rules! vector_kernels {
+    ($v:ty, $features:literal) => {
+        pub static KERNELS: Kernels = Kernels {
+            dot,
+            scale_mul,
+            silu_mul,
+            max,
+            exp_sum,
+            scale,
+            axpy,
+        };
+
+        // SAFETY (all below): only reachable through Isa::kernels, which checks
+        // that the CPU has the features first
+        fn dot(a: &[f32], b: &[f32]) -> f32 {
+            #[target_feature(enable = $features)]
+            unsafe fn imp(a: &[f32], b: &[f32]) -> f32 {
+                vector::dot::<$v>(a, b)
+            }
+            unsafe { imp(a, b) }
+        }
+
+        fn scale_mul(y: &mut [f32], x: &[f32], w: &[f32], s: f32) {
+            #[target_feature(enable = $features)]
+            unsafe fn imp(y: &mut [f32], x: &[f32], w: &[f32], s: f32) {
+                vector::scale_mul::<$v>(y, x, w, s)
+            }
+            unsafe { imp(y, x, w, s) }
+        }
+
+        fn silu_mul(y: &mut [f32], x: &[f32]) {
+            #[target_feature(enable = $features)]
+            unsafe fn imp(y: &mut [f32], x: &[f32]) {
+                vector::silu_mul::<$v>(y, x)
+            }
+            unsafe { imp(y, x) }
+        }
+
+        fn max(x: &[f32]) -> f32 {
+            #[target_feature(enable = $features)]
+            unsafe fn imp(x: &[f32]) -> f32 {
+                vector::max::<$v>(x)
+            }
+            unsafe { imp(x) }
+        }
+
+        fn exp_sum(x: &mut [f32], m: f32) -> f32 {
+            #[target_feature(enable = $features)]
+            unsafe fn imp(x: &mut [f32], m: f32) -> f32 {
+                vector::exp_sum::<$v>(x, m)
+            }
+            unsafe { imp(x, m) }
+        }
+
+        fn scale(x: &mut [f32], s: f32) {
+            #[target_feature(enable = $features)]
+            unsafe fn imp(x: &mut [f32], s: f32) {
+                vector::scale::<$v>(x, s)
+            }
+            unsafe { imp(x, s) }
+        }
+
+        fn axpy(y: &mut [f32], a: f32, x: &[f32]) {
+            #[target_feature(enable = $features)]
+            unsafe fn imp(y: &mut [f32], a: f32, x: &[f32]) {
+                vector::axpy::<$v>(y, a, x)
+            }
+            unsafe { imp(y, a, x) }
+        }
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use super::{vector, Kernels, Vector};
+    use std::arch::x86_64::*;
+
+    #[derive(Clone, Copy)]
+    struct V(__m256);
+
+    impl Vector for V {
+        const LANES: usize = 8;
+        #[inline(always)]
+        unsafe fn splat(x: f32) -> Self {
+            V(_mm256_set1_ps(x))
+        }
+        #[inline(always)]
+        unsafe fn load(p: *const f32) -> Self {
+            V(_mm256_loadu_ps(p))
+        }
+        #[inline(always)]
+        unsafe fn store(self, p: *mut f32) {
+            _mm256_storeu_ps(p, self.0)
+        }
+        #[inline(always)]
+        unsafe fn add(self, b: Self) -> Self {
+            V(_mm256_add_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, b: Self) -> Self {
+            V(_mm256_mul_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn div(self, b: Self) -> Self {
+            V(_mm256_div_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn min(self, b: Self) -> Self {
+            V(_mm256_min_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn max(self, b: Self) -> Self {
+            V(_mm256_max_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn mul_add(self, b: Self, c: Self) -> Self {
+            V(_mm256_fmadd_ps(self.0, b.0, c.0))
+        }
+        #[inline(always)]
+        unsafe fn round(self) -> Self {
+            V(_mm256_round_ps::<
+                { _MM_FROUND_TO_NEAREST_INT | _MM_FROUND_NO_EXC },
+            >(self.0))
+        }
+        #[inline(always)]
+        unsafe fn exp2_int(self) -> Self {
+            let e = _mm256_add_epi32(_mm256_cvtps_epi32(self.0), _mm256_set1_epi32(127));
+            V(_mm256_castsi256_ps(_mm256_slli_epi32::<23>(e)))
+        }
+        #[inline(always)]
+        unsafe fn sum(self) -> f32 {
+            let s = _mm_add_ps(
+                _mm256_castps256_ps128(self.0),
+                _mm256_extractf128_ps::<1>(self.0),
+            );
+            let s = _mm_add_ps(s, _mm_movehl_ps(s, s));
+            _mm_cvtss_f32(_mm_add_ss(s, _mm_movehdup_ps(s)))
+        }
+        #[inline(always)]
+        unsafe fn max_lane(self) -> f32 {
+            let m = _mm_max_ps(
+                _mm256_castps256_ps128(self.0),
+                _mm256_extractf128_ps::<1>(self.0),
+            );
+            let m = _mm_max_ps(m, _mm_movehl_ps(m, m));
+            _mm_cvtss_f32(_mm_max_ss(m, _mm_movehdup_ps(m)))
+        }
+    }
+
+    vector_kernels!(V, "avx2,fma");
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx512 {
+    use super::{vector, Kernels, Vector};
+    use std::arch::x86_64::*;
+
+    #[derive(Clone, Copy)]
+    struct V(__m512);
+
+    impl Vector for V {
+        const LANES: usize = 16;
+        #[inline(always)]
+        unsafe fn splat(x: f32) -> Self {
+            V(_mm512_set1_ps(x))
+        }
+        #[inline(always)]
+        unsafe fn load(p: *const f32) -> Self {
+            V(_mm512_loadu_ps(p))
+        }
+        #[inline(always)]
+        unsafe fn store(self, p: *mut f32) {
+            _mm512_storeu_ps(p, self.0)
+        }
+        #[inline(always)]
+        unsafe fn add(self, b: Self) -> Self {
+            V(_mm512_add_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, b: Self) -> Self {
+            V(_mm512_mul_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn div(self, b: Self) -> Self {
+            V(_mm512_div_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn min(self, b: Self) -> Self {
+            V(_mm512_min_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn max(self, b: Self) -> Self {
+            V(_mm512_max_ps(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn mul_add(self, b: Self, c: Self) -> Self {
+            V(_mm512_fmadd_ps(self.0, b.0, c.0))
+        }
+        #[inline(always)]
+        unsafe fn round(self) -> Self {
+            V(_mm512_roundscale_ps::<
+                { _MM_FROUND_TO_NEAREST_INT | _MM_FROUND_NO_EXC },
+            >(self.0))
+        }
+        #[inline(always)]
+        unsafe fn exp2_int(self) -> Self {
+            let e = _mm512_add_epi32(_mm512_cvtps_epi32(self.0), _mm512_set1_epi32(127));
+            V(_mm512_castsi512_ps(_mm512_slli_epi32::<23>(e)))
+        }
+        #[inline(always)]
+        unsafe fn sum(self) -> f32 {
+            _mm512_reduce_add_ps(self.0)
+        }
+        #[inline(always)]
+        unsafe fn max_lane(self) -> f32 {
+            _mm512_reduce_max_ps(self.0)
+        }
+    }
+
+    vector_kernels!(V, "avx512f");
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use super::{vector, Kernels, Vector};
+    use std::arch::aarch64::*;
+
+    #[derive(Clone, Copy)]
+    struct V(float32x4_t);
+
+    impl Vector for V {
+        const LANES: usize = 4;
+        #[inline(always)]
+        unsafe fn splat(x: f32) -> Self {
+            V(vdupq_n_f32(x))
+        }
+        #[inline(always)]
+        unsafe fn load(p: *const f32) -> Self {
+            V(vld1q_f32(p))
+        }
+        #[inline(always)]
+        unsafe fn store(self, p: *mut f32) {
+            vst1q_f32(p, self.0)
+        }
+        #[inline(always)]
+        unsafe fn add(self, b: Self) -> Self {
+            V(vaddq_f32(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, b: Self) -> Self {
+            V(vmulq_f32(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn div(self, b: Self) -> Self {
+            V(vdivq_f32(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn min(self, b: Self) -> Self {
+            V(vminq_f32(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn max(self, b: Self) -> Self {
+            V(vmaxq_f32(self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn mul_add(self, b: Self, c: Self) -> Self {
+            V(vfmaq_f32(c.0, self.0, b.0))
+        }
+        #[inline(always)]
+        unsafe fn round(self) -> Self {
+            V(vrndnq_f32(self.0))
+        }
+        #[inline(always)]
+        unsafe fn exp2_int(self) -> Self {
+            let e = vaddq_s32(vcvtq_s32_f32(self.0), vdupq_n_s32(127));
+            V(vreinterpretq_f32_s32(vshlq_n_s32::<23>(e)))
+        }
+        #[inline(always)]
+        unsafe fn sum(self) -> f32 {
+            vaddvq_f32(self.0)
+        }
+        #[inline(always)]
+        unsafe fn max_lane(self) -> f32 {
+            vmaxvq_f32(self.0)
+        }
+    }
+
+    vector_kernels!(V, "neon");
+}
+
+// Lengths around the vector widths, so that every kernel runs its vector
+// loops and its scalar tail
+#[cfg(test)]
+const TEST_LENS: [usize; 12] = [0, 1, 3, 4, 7, 8, 15, 16, 17, 33, 64, 259];
+
+#[cfg(test)]
+fn scaled(values: Vec<f32>, scale: f32) -> Vec<f32> {
+    values.into_iter().map(|x| x * scale).collect()
+}
+
+#[cfg(test)]
+fn assert_close(x: &[f32], y: &[f32], rel: f32, isa: Isa) {
+    assert_eq!(x.len(), y.len());
+    for (a, b) in x.iter().zip(y) {
+        assert!(
+            (a - b).abs() <= rel * a.abs().max(b.abs()) + 1e-30,
+            "{isa:?}: {a} {b}"
+        );
+    }
+}
+
+#[test]
+fn test_kernels_match_scalar() {
+    use crate::test_util::values;
+    let scalar = Isa::Scalar.kernels().unwrap();
+    let supported = Isa::ALL.into_iter().filter(|isa| isa.is_supported());
+    for isa in supported.filter(|&isa| isa != Isa::Scalar) {
+        let k = isa.kernels().unwrap();
+        for len in TEST_LENS {
+            let (a, b) = (scaled(values(len, 0.37), 2.), scaled(values(len, 0.91), 3.));
+            // only the order of the sums differs
+            let tolerance = 1e-5 * (len as f32).max(1.) * 6.;
+            assert!(
+                (k.dot(&a, &b) - scalar.dot(&a, &b)).abs() <= tolerance,
+                "{isa:?}"
+            );
+
+            let w = values(len, 0.13);
+            let (mut y, mut expected) = (vec![0.; len], vec![0.; len]);
+            k.scale_mul(&mut y, &a, &w, 0.7);
+            scalar.scale_mul(&mut expected, &a, &w, 0.7);
+            assert_close(&y, &expected, 1e-6, isa);
+
+            // x spans both saturated ends of the sigmoid
+            let x = scaled(values(len, 0.53), 120.);
+            let (mut y, mut expected) = (b.clone(), b.clone());
+            k.silu_mul(&mut y, &x);
+            scalar.silu_mul(&mut expected, &x);
+            assert_close(&y, &expected, 1e-5, isa);
+
+            let x = scaled(values(len, 0.29), 30.);
+            assert_eq!(k.max(&x), scalar.max(&x), "{isa:?}");
+            let (mut y, mut expected) = (x.clone(), x.clone());
+            let m = scalar.max(&x);
+            let sum = k.exp_sum(&mut y, m);
+            let expected_sum = scalar.exp_sum(&mut expected, m);
+            assert_close(&y, &expected, 1e-5, isa);
+            assert_close(&[sum], &[expected_sum], 1e-5, isa);
+            k.scale(&mut y, 1. / sum);
+            scalar.scale(&mut expected, 1. / expected_sum);
+            assert_close(&y, &expected, 1e-5, isa);
+
+            let (mut y, mut expected) = (b.clone(), b.clone());
+            k.axpy(&mut y, 0.7, &a);
+            scalar.axpy(&mut expected, 0.7, &a);
+            // a fused multiply-add rounds once, relative to the terms
+            for ((y, e), (a, b)) in y.iter().zip(&expected).zip(a.iter().zip(&b)) {
+                assert!((y - e).abs() <= 1e-6 * (b.abs() + 0.7 * a.abs()), "{isa:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_exp_range() {
+    // exp over the whole range where it is a normal f32, and past both ends
+    let x: Vec<f32> = (-1000..=1000).map(|i| i as f32 * 0.0875).collect();
+    let expected: Vec<f32> = x.iter().map(|x| x.exp()).collect();
+    for isa in Isa::ALL.into_iter().filter(|isa| isa.is_supported()) {
+        let mut y = x.clone();
+        isa.kernels().unwrap().exp_sum(&mut y, 0.);
+        for (x, (a, b)) in x.iter().zip(y.iter().zip(&expected)) {
+            if x.abs() < 87. {
+                assert!(
+                    (a - b).abs() <= 1e-6 * b,
+                    "{isa:?}: exp({x}) = {a}, not {b}"
+                );
+            } else {
+                // saturated, but on the right side
+                assert!((*x < 0.) == (*a < 1e-37), "{isa:?}: exp({x}) = {a}");
+            }
+        }
+    }
+}
+
+// cargo test --release bench_kernels -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_kernels() {
+    use crate::test_util::values;
+    use std::time::Instant;
+    let runs = 100_000;
+    let (a, b) = (values(2048, 0.37), values(2048, 0.91));
+    for isa in Isa::ALL.into_iter().filter(|isa| isa.is_supported()) {
+        let k = isa.kernels().unwrap();
+        let start = Instant::now();
+        let mut sum = 0.;
+        for _ in 0..runs {
+            sum += k.dot(&a, &b);
+        }
+        let dot = start.elapsed() / runs;
+        let mut y = a.clone();
+        let start = Instant::now();
+        for _ in 0..runs {
+            let m = k.max(&y);
+            let s = k.exp_sum(&mut y, m);
+            k.scale(&mut y, 1. / s);
+        }
+        let softmax = start.elapsed() / runs;
+        let mut y = b.clone();
+        let start = Instant::now();
+        for _ in 0..runs {
+            // keep y from decaying to subnormals
+            y.copy_from_slice(&b);
+            k.silu_mul(&mut y, &a);
+        }
+        let silu = start.elapsed() / runs;
+        println!("{isa:?} (2048 wide): dot {dot:?}, softmax {softmax:?}, swiglu {silu:?} ({sum})");
+    }
+}
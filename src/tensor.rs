@@ -1,23 +1,111 @@
+use half::slice::HalfFloatSliceExt;
+use half::{bf16, f16};
+use memmap2::Mmap;
+use safetensors::Dtype;
+use std::ops::Deref;
 use std::{slice, sync::Arc, vec};
+
+// Element types a model can be stored and run in. Operators widen
+// elements to f32 for arithmetic and narrow the results back.
+pub trait Float: Copy + Default + Send + Sync + 'static {
+    // safetensors dtype with the same little-endian layout
+    const DTYPE: Dtype;
+    fn from_f32(x: f32) -> Self;
+    fn to_f32(self) -> f32;
+    // Widen x into dst, which is as long
+    fn slice_to_f32(x: &[Self], dst: &mut [f32]) {
+        dst.iter_mut().zip(x).for_each(|(d, x)| *d = x.to_f32());
+    }
+}
+
+impl Float for f32 {
+    const DTYPE: Dtype = Dtype::F32;
+    #[inline(always)]
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+impl Float for f16 {
+    const DTYPE: Dtype = Dtype::F16;
+    #[inline(always)]
+    fn from_f32(x: f32) -> Self {
+        f16::from_f32(x)
+    }
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+    // several at a time with F16C
+    fn slice_to_f32(x: &[Self], dst: &mut [f32]) {
+        x.convert_to_f32_slice(dst)
+    }
+}
+
+impl Float for bf16 {
+    const DTYPE: Dtype = Dtype::BF16;
+    #[inline(always)]
+    fn from_f32(x: f32) -> Self {
+        bf16::from_f32(x)
+    }
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+    fn slice_to_f32(x: &[Self], dst: &mut [f32]) {
+        x.convert_to_f32_slice(dst)
+    }
+}
+
+// Backing buffer of a tensor, either owned or borrowed from a memory-mapped file
+enum Storage<T> {
+    Owned(Box<[T]>),
+    // `len` elements starting `start` bytes into a read-only map
+    Mapped {
+        mmap: Arc<Mmap>,
+        start: usize,
+        len: usize,
+    },
+}
+
+impl<T> Deref for Storage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Storage::Owned(data) => data,
+            // SAFETY: `Tensor::mapped` checked the bounds and alignment of the range
+            Storage::Mapped { mmap, start, len } => unsafe {
+                slice::from_raw_parts(mmap.as_ptr().add(*start) as *const T, *len)
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Tensor<T> {
-    data: Arc<Box<[T]>>,
+    data: Arc<Storage<T>>,
     shape: Vec<usize>,
     offset: usize,
     length: usize,
 }
 
 impl<T: Copy + Clone + Default> Tensor<T> {
-    pub fn new(data: Vec<T>, shape: &Vec<usize>) -> Self {
+    pub fn new(data: Vec<T>, shape: &[usize]) -> Self {
         let length = data.len();
         Tensor {
-            data: Arc::new(data.into_boxed_slice().try_into().unwrap()),
-            shape: shape.clone(),
+            data: Arc::new(Storage::Owned(data.into_boxed_slice())),
+            shape: shape.to_vec(),
             offset: 0,
-            length: length,
+            length,
         }
     }
 
-    pub fn default(shape: &Vec<usize>) -> Self {
+    pub fn default(shape: &[usize]) -> Self {
         let length = shape.iter().product();
         let data = vec![T::default(); length];
         Self::new(data, shape)
@@ -28,10 +116,24 @@ impl<T: Copy + Clone + Default> Tensor<T> {
     }
 
     pub unsafe fn data_mut(&mut self) -> &mut [T] {
+        assert!(
+            matches!(*self.data, Storage::Owned(_)),
+            "memory-mapped tensors are read-only"
+        );
         let ptr = self.data.as_ptr().add(self.offset) as *mut T;
         slice::from_raw_parts_mut(ptr, self.length)
     }
 
+    // data_mut for a tensor that owns its buffer alone, as the buffers of a
+    // forward pass do, so that the borrow checker keeps the access exclusive
+    pub fn data_mut_owned(&mut self) -> &mut [T] {
+        match Arc::get_mut(&mut self.data) {
+            Some(Storage::Owned(data)) => &mut data[self.offset..][..self.length],
+            Some(Storage::Mapped { .. }) => panic!("memory-mapped tensors are read-only"),
+            None => panic!("the tensor shares its buffer"),
+        }
+    }
+
     pub fn shape(&self) -> &Vec<usize> {
         &self.shape
     }
@@ -41,28 +143,58 @@ impl<T: Copy + Clone + Default> Tensor<T> {
     }
 
     // Reinterpret the tensor as a new shape while preserving total size.
-    pub fn reshape(&mut self, new_shape: &Vec<usize>) -> &mut Self {
+    pub fn reshape(&mut self, new_shape: &[usize]) -> &mut Self {
         let new_length: usize = new_shape.iter().product();
         if new_length != self.length {
             let old_shape = self.shape.clone();
             panic!("New shape {new_shape:?} does not match tensor of {old_shape:?}");
         }
-        self.shape = new_shape.clone();
+        self.shape = new_shape.to_vec();
         self
     }
 
-    pub fn slice(&self, start: usize, shape: &Vec<usize>) -> Self {
+    pub fn slice(&self, start: usize, shape: &[usize]) -> Self {
         let new_length: usize = shape.iter().product();
         assert!(self.offset + start + new_length <= self.length);
         Tensor {
             data: self.data.clone(),
-            shape: shape.clone(),
+            shape: shape.to_vec(),
             offset: self.offset + start,
             length: new_length,
         }
     }
 }
 
+impl<T: Float> Tensor<T> {
+    // Borrow little-endian `T::DTYPE` data starting `start` bytes into `mmap` without
+    // copying. Returns None if the range is out of bounds or not aligned for T.
+    pub fn mapped(mmap: Arc<Mmap>, start: usize, shape: &[usize]) -> Option<Self> {
+        let length: usize = shape.iter().product();
+        let end = start.checked_add(length.checked_mul(size_of::<T>())?)?;
+        if cfg!(target_endian = "big")
+            || end > mmap.len()
+            || !(mmap.as_ptr() as usize + start).is_multiple_of(align_of::<T>())
+        {
+            return None;
+        }
+        Some(Tensor {
+            data: Arc::new(Storage::Mapped {
+                mmap,
+                start,
+                len: length,
+            }),
+            shape: shape.to_vec(),
+            offset: 0,
+            length,
+        })
+    }
+
+    #[allow(unused)]
+    pub fn is_mapped(&self) -> bool {
+        matches!(*self.data, Storage::Mapped { .. })
+    }
+}
+
 // Some helper functions for testing and debugging
 impl Tensor<f32> {
     #[allow(unused)]
@@ -73,7 +205,7 @@ impl Tensor<f32> {
         let a = self.data();
         let b = other.data();
 
-        return a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel));
+        a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel))
     }
     #[allow(unused)]
     pub fn print(&self) {
@@ -94,3 +226,38 @@ impl Tensor<f32> {
 pub fn float_eq(x: &f32, y: &f32, rel: f32) -> bool {
     (x - y).abs() <= rel * (x.abs() + y.abs()) / 2.0
 }
+
+#[test]
+fn test_mapped_tensor() {
+    use std::io::Write;
+    let path = std::env::temp_dir().join(format!("learning-lm-rs-mmap-{}", std::process::id()));
+    let mut file = std::fs::File::create(&path).unwrap();
+    for x in [0f32, 1., 2., 3., 4.] {
+        file.write_all(&x.to_le_bytes()).unwrap();
+    }
+    {
+        let mmap = Arc::new(unsafe { Mmap::map(&std::fs::File::open(&path).unwrap()) }.unwrap());
+        let t = Tensor::<f32>::mapped(mmap.clone(), 4, &[2, 2]).unwrap();
+        assert!(t.is_mapped());
+        assert_eq!(t.data(), &[1., 2., 3., 4.]);
+        assert_eq!(t.slice(2, &[2]).data(), &[3., 4.]);
+        // misaligned or out of bounds
+        assert!(Tensor::<f32>::mapped(mmap.clone(), 2, &[2]).is_none());
+        assert!(Tensor::<f32>::mapped(mmap, 8, &[4]).is_none());
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_data_mut_owned() {
+    let mut t = Tensor::<f32>::new(vec![0., 1., 2., 3.], &[2, 2]);
+    t.data_mut_owned()[1] = 5.;
+    assert_eq!(t.data(), &[0., 5., 2., 3.]);
+    // a slice shares the buffer, so neither may write through it
+    let mut slice = t.slice(2, &[2]);
+    let shared = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        slice.data_mut_owned()[0] = 1.;
+    }));
+    assert!(shared.is_err());
+    assert_eq!(t.data(), &[0., 5., 2., 3.]);
+}
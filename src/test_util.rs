@@ -0,0 +1,31 @@
+// Fixtures and checks shared by the tests of several modules
+use crate::model::Llama;
+use std::path::PathBuf;
+
+// The directory of the story model that comes with the repo
+pub fn story_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("models")
+        .join("story")
+}
+
+pub fn story_model() -> Llama<f32> {
+    Llama::from_safetensors(story_dir()).unwrap()
+}
+
+// n tokens spread over the story model's vocabulary
+pub fn test_tokens(n: usize) -> Vec<u32> {
+    (0..n as u32).map(|i| 1 + i * 97 % 2000).collect()
+}
+
+// Deterministic values in [-1, 1], different for every seed
+pub fn values(len: usize, seed: f32) -> Vec<f32> {
+    (0..len).map(|i| (i as f32 * seed).sin()).collect()
+}
+
+pub fn max_abs_diff(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    (a.iter().zip(b))
+        .map(|(a, b)| (a - b).abs())
+        .fold(0f32, f32::max)
+}